@@ -6,16 +6,28 @@ pub struct BotDescription;
 
 #[async_trait]
 impl ExecutableCommand<MyState> for BotDescription {
-    async fn execute<'a>(&self, _: &'a str, message: irc::Message<'a>, _: &BotState<MyState>) -> ExecutionOutcome {
-        ExecutionOutcome::success(
-            message.first_arg_as_channel_name().unwrap().to_string(),
-            "\
-            FeelsDankMan I'm a bot by modelflat. \
-            Prefix: '>>'. \
-            Language: Rust (nightly). \
-            See (>> help) for commands. \
-            Source code at github: modelflat/twitchbot"
-                .to_string(),
+    async fn execute<'a>(
+        &self,
+        _: &'a str,
+        message: irc::Message<'a>,
+        _: &'a twitch::Event,
+        state: &BotState<MyState>,
+    ) -> ExecutionOutcome {
+        let prefix = match message.channel() {
+            Some(channel) => state.prefix_for(channel),
+            None => &state.prefix,
+        };
+        ExecutionOutcome::respond(
+            &message,
+            format!(
+                "\
+                FeelsDankMan I'm a bot by modelflat. \
+                Prefix: '{}'. \
+                Language: Rust (nightly). \
+                See ({} help) for commands. \
+                Source code at github: modelflat/twitchbot",
+                prefix, prefix
+            ),
         )
     }
 
@@ -10,16 +10,14 @@ impl ExecutableCommand<MyState> for Echo {
         &self,
         command: &'a str,
         message: irc::Message<'a>,
+        _: &'a twitch::Event,
         _: &BotState<MyState>,
     ) -> ExecutionOutcome {
         if command.is_empty() {
             info!("nothing to echo!");
             ExecutionOutcome::SilentSuccess
         } else {
-            ExecutionOutcome::success(
-                message.first_arg_as_channel_name().unwrap().to_string(),
-                command.to_string(),
-            )
+            ExecutionOutcome::respond(&message, command.to_string())
         }
     }
 
@@ -10,10 +10,11 @@ impl ExecutableCommand<MyState> for Help {
         &self,
         command: &'a str,
         message: irc::Message<'a>,
+        _: &'a twitch::Event,
         state: &BotState<MyState>,
     ) -> ExecutionOutcome {
-        ExecutionOutcome::success(
-            message.first_arg_as_channel_name().unwrap().to_string(),
+        ExecutionOutcome::respond(
+            &message,
             if command.is_empty() {
                 format!("commands: {}", {
                     let mut cmds: Vec<String> = state.commands.keys().map(|k| k.to_owned()).collect();
@@ -46,4 +47,9 @@ impl ExecutableCommand<MyState> for Help {
     fn level(&self) -> PermissionLevel {
         PermissionLevel::User
     }
+
+    fn overflow(&self) -> Overflow {
+        // list of commands is better seen in full
+        Overflow::Split
+    }
 }
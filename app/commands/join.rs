@@ -0,0 +1,45 @@
+use bot::prelude::*;
+
+use super::MyState;
+
+pub struct Join;
+
+#[async_trait]
+impl ExecutableCommand<MyState> for Join {
+    async fn execute<'a>(
+        &self,
+        command: &'a str,
+        message: irc::Message<'a>,
+        _: &'a twitch::Event,
+        state: &BotState<MyState>,
+    ) -> ExecutionOutcome {
+        match command.split_whitespace().next() {
+            Some(channel) => match state.join_channel(channel).await {
+                Ok(()) => ExecutionOutcome::respond(&message, format!("joining {}", channel)),
+                Err(ChannelRequestError::InvalidName(_)) => {
+                    ExecutionOutcome::respond(&message, format!("{} is not a valid channel name", channel))
+                }
+                Err(err) => ExecutionOutcome::Error(format!("failed to request join: {}", err)),
+            },
+            None => {
+                info!("no channel to join!");
+                ExecutionOutcome::SilentSuccess
+            }
+        }
+    }
+
+    fn help(&self) -> String {
+        "join <channel> -- joins channel".to_string()
+    }
+
+    fn cooldown(&self) -> CommandCooldown {
+        CommandCooldown {
+            command: Some(Duration::from_secs(1)),
+            user: None,
+        }
+    }
+
+    fn level(&self) -> PermissionLevel {
+        PermissionLevel::Admin
+    }
+}
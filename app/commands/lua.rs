@@ -1,9 +1,28 @@
-use bot::lua::run_untrusted_lua_code;
+use bot::lua::{LuaContext, LuaPool, LuaStore};
 use bot::prelude::*;
 
 use super::MyState;
 
-pub struct Lua;
+/// How many scripts can run at the same time.
+const THREADS: usize = 2;
+
+/// How many scripts can wait for their turn to run.
+const QUEUE_SIZE: usize = 8;
+
+pub struct Lua {
+    pool: LuaPool,
+    /// Whether users have a separate store in every channel, rather than one they take everywhere.
+    store_per_channel: bool,
+}
+
+impl Lua {
+    pub fn new(store_per_channel: bool) -> Lua {
+        Lua {
+            pool: LuaPool::new(THREADS, QUEUE_SIZE),
+            store_per_channel,
+        }
+    }
+}
 
 #[async_trait]
 impl ExecutableCommand<MyState> for Lua {
@@ -11,25 +30,49 @@ impl ExecutableCommand<MyState> for Lua {
         &self,
         command: &'a str,
         message: irc::Message<'a>,
-        _: &BotState<MyState>,
+        event: &'a twitch::Event,
+        state: &BotState<MyState>,
     ) -> ExecutionOutcome {
         if !command.is_empty() {
-            let user = message.tag_value("display-name").unwrap_or("<no-display-name>");
+            let context = LuaContext::of(event, command);
 
-            info!("{} is executing Lua: {}", user, command);
+            info!("{} is executing Lua: {}", context.display_name, command);
 
             let instructions = 1 << 10;
 
             // ought to be enough for anyone
             let memory = 640 * (1 << 10);
 
-            let result = run_untrusted_lua_code(command.to_string(), instructions, memory);
+            let time = Duration::from_secs(1);
+
+            let context = LuaContext {
+                last_message: match &context.channel {
+                    Some(channel) => state.chat.last_message(channel, &context.login),
+                    None => None,
+                },
+                store: if context.login.is_empty() {
+                    None
+                } else {
+                    let channel = context.channel.as_ref().filter(|_| self.store_per_channel);
+                    Some(LuaStore::new(
+                        state.storage.clone(),
+                        &context.login,
+                        channel.map(|channel| channel.as_str()),
+                    ))
+                },
+                ..context
+            };
+
+            let result = self
+                .pool
+                .run(command.to_string(), context, instructions, memory, time)
+                .await;
 
-            ExecutionOutcome::success(
-                message.first_arg_as_channel_name().unwrap().to_string(),
+            ExecutionOutcome::reply(
+                &message,
                 match result {
-                    Ok(result) => format!("@{}, ({}) res = {}", user, result.instructions_left, result.result),
-                    Err(err) => format!("@{}, error! {}", user, err),
+                    Ok(result) => format!("({}) res = {}", result.instructions_left, result.result),
+                    Err(err) => format!("error! {}", err),
                 },
             )
         } else {
@@ -39,8 +82,9 @@ impl ExecutableCommand<MyState> for Lua {
     }
 
     fn help(&self) -> String {
-        "lua <code> -- executes your code in a Lua sandbox. \
-        limits: 640kb of memory, ~1000 instructions FeelsGoodMan"
+        "lua <code> -- executes your code in a Lua sandbox, see ctx, random(), emotes(), last_message() \
+        and store.get/set to keep data between runs. \
+        limits: 640kb of memory, ~1000 instructions, 1 second FeelsGoodMan"
             .to_string()
     }
 
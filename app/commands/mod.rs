@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use bot::prelude::*;
 
+use crate::config::Config;
+
 mod help;
 use help::Help;
 
@@ -14,6 +16,12 @@ use echo::Echo;
 mod lua;
 use lua::Lua;
 
+mod join;
+use join::Join;
+
+mod part;
+use part::Part;
+
 pub struct MyState;
 
 impl MyState {
@@ -26,17 +34,13 @@ pub fn state() -> MyState {
     MyState::new()
 }
 
-pub fn commands() -> HashMap<String, ShareableExecutableCommand<MyState>> {
+pub fn commands(config: &Config) -> HashMap<String, ShareableExecutableCommand<MyState>> {
     let mut map: HashMap<String, ShareableExecutableCommand<MyState>> = HashMap::new();
     map.insert("bot".to_string(), Box::new(BotDescription {}));
     map.insert("echo".to_string(), Box::new(Echo {}));
-    map.insert("lua".to_string(), Box::new(Lua {}));
+    map.insert("lua".to_string(), Box::new(Lua::new(config.store_per_channel)));
     map.insert("help".to_string(), Box::new(Help {}));
+    map.insert("join".to_string(), Box::new(Join {}));
+    map.insert("part".to_string(), Box::new(Part {}));
     map
 }
-
-pub fn permissions() -> PermissionList {
-    let mut map = HashMap::new();
-    map.insert("modelflat".to_string(), PermissionLevel::Admin);
-    PermissionList::new(map)
-}
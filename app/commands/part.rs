@@ -0,0 +1,42 @@
+use bot::prelude::*;
+
+use super::MyState;
+
+pub struct Part;
+
+#[async_trait]
+impl ExecutableCommand<MyState> for Part {
+    async fn execute<'a>(
+        &self,
+        command: &'a str,
+        message: irc::Message<'a>,
+        _: &'a twitch::Event,
+        _: &BotState<MyState>,
+    ) -> ExecutionOutcome {
+        match command.split_whitespace().next() {
+            Some(channel) => match valid_channel_name(channel) {
+                Ok(name) => ExecutionOutcome::respond(&message, format!("leaving {}", channel)).then_part(name),
+                Err(_) => ExecutionOutcome::respond(&message, format!("{} is not a valid channel name", channel)),
+            },
+            None => {
+                info!("no channel to leave!");
+                ExecutionOutcome::SilentSuccess
+            }
+        }
+    }
+
+    fn help(&self) -> String {
+        "part <channel> -- leaves channel".to_string()
+    }
+
+    fn cooldown(&self) -> CommandCooldown {
+        CommandCooldown {
+            command: Some(Duration::from_secs(1)),
+            user: None,
+        }
+    }
+
+    fn level(&self) -> PermissionLevel {
+        PermissionLevel::Admin
+    }
+}
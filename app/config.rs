@@ -0,0 +1,642 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use url::Url;
+
+use bot::banphrase::{
+    BanphraseChecker, CachedChecker, CompositeChecker, FailurePolicy, GuardedChecker, LocalRules, PajbotAPI,
+    PerChannelChecker, RulesError, DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL, DEFAULT_CHECK_RETRIES,
+    DEFAULT_CHECK_TIMEOUT,
+};
+use bot::prelude::*;
+use bot::Settings;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ConfigError::Io(path, err) => f.write_fmt(format_args!("cannot read {}: {}", path.display(), err)),
+            ConfigError::Parse(err) => f.write_fmt(format_args!("cannot parse configuration: {}", err)),
+            ConfigError::Invalid(problems) => {
+                f.write_str("invalid configuration:")?;
+                for problem in problems {
+                    f.write_fmt(format_args!("\n  - {}", problem))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Where to take a secret (or any other value which shouldn't necessarily be stored in config) from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Environment variable with a given name.
+    Env(String),
+    /// File with a given path. Leading and trailing whitespace is ignored.
+    File(PathBuf),
+    /// Value written in config as is.
+    Value(String),
+}
+
+impl Source {
+    pub fn resolve(&self) -> Result<String, ConfigError> {
+        match self {
+            Source::Env(name) => std::env::var(name)
+                .map_err(|_| ConfigError::Invalid(vec![format!("environment variable {} is not set", name)])),
+            Source::File(path) => std::fs::read_to_string(path)
+                .map(|s| s.trim().to_string())
+                .map_err(|err| ConfigError::Io(path.clone(), err)),
+            Source::Value(value) => Ok(value.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    pub username: Source,
+    pub token: Source,
+}
+
+impl Default for Credentials {
+    fn default() -> Credentials {
+        Credentials {
+            username: Source::Env("TWITCH_USERNAME".to_string()),
+            token: Source::Env("TWITCH_OAUTH_TOKEN".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub prefix: Option<String>,
+    /// Banphrase API of channel's own pajbot instance, used instead of `banphrase.url`. Empty string means
+    /// that channel doesn't have one.
+    pub banphrase_url: Option<String>,
+    /// What to do when banphrase API fails, instead of `banphrase.on_failure`.
+    pub banphrase_on_failure: Option<FailurePolicy>,
+}
+
+/// Cooldowns, in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CooldownConfig {
+    /// Cooldown between messages sent to a channel.
+    pub channel: f64,
+    /// How long sent messages are remembered to avoid duplicates.
+    pub history_ttl: f64,
+}
+
+impl Default for CooldownConfig {
+    fn default() -> CooldownConfig {
+        let settings = Settings::default();
+        CooldownConfig {
+            channel: settings.channel_cooldown.as_secs_f64(),
+            history_ttl: settings.history_ttl.as_secs_f64(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanphraseConfig {
+    /// Pajbot banphrase API used for channels which don't specify their own. Empty string disables it.
+    pub url: String,
+    /// File with banned phrases checked locally in every channel.
+    pub rules: Option<PathBuf>,
+    /// How long a request to banphrase API can take, in seconds.
+    pub timeout: f64,
+    /// How many times a failed request is retried.
+    pub retries: usize,
+    /// What to do when banphrase API fails.
+    pub on_failure: FailurePolicy,
+    /// How many verdicts of banphrase API are remembered.
+    pub cache_size: usize,
+    /// How long verdicts of banphrase API are remembered, in seconds.
+    pub cache_ttl: f64,
+}
+
+impl Default for BanphraseConfig {
+    fn default() -> BanphraseConfig {
+        BanphraseConfig {
+            url: Settings::default().banphrase_api_url,
+            rules: None,
+            timeout: DEFAULT_CHECK_TIMEOUT.as_secs_f64(),
+            retries: DEFAULT_CHECK_RETRIES,
+            on_failure: FailurePolicy::default(),
+            cache_size: DEFAULT_CACHE_CAPACITY,
+            cache_ttl: DEFAULT_CACHE_TTL.as_secs_f64(),
+        }
+    }
+}
+
+/// Overrides of command properties. Cooldowns are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandConfig {
+    pub enabled: bool,
+    pub cooldown: Option<f64>,
+    pub user_cooldown: Option<f64>,
+    pub level: Option<PermissionLevel>,
+    pub overflow: Option<Overflow>,
+}
+
+impl Default for CommandConfig {
+    fn default() -> CommandConfig {
+        CommandConfig {
+            enabled: true,
+            cooldown: None,
+            user_cooldown: None,
+            level: None,
+            overflow: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub url: String,
+    pub credentials: Credentials,
+    pub channels: Vec<String>,
+    pub prefix: String,
+    pub concurrency: usize,
+    /// How long to wait for queued commands and messages on shutdown, in seconds.
+    pub shutdown_timeout: f64,
+    /// Directory to keep data in between restarts. Nothing is kept if not set.
+    pub storage: Option<PathBuf>,
+    /// Whether users have a separate Lua store in every channel, rather than one they take everywhere.
+    pub store_per_channel: bool,
+    /// Per-channel settings, keyed by channel name.
+    pub channel: HashMap<String, ChannelConfig>,
+    pub cooldowns: CooldownConfig,
+    pub banphrase: BanphraseConfig,
+    /// Permission levels, keyed by user name.
+    pub permissions: HashMap<String, PermissionLevel>,
+    /// Per-command overrides, keyed by command name.
+    pub commands: HashMap<String, CommandConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let settings = Settings::default();
+        Config {
+            url: "wss://irc-ws.chat.twitch.tv:443".to_string(),
+            credentials: Credentials::default(),
+            channels: settings.channels,
+            prefix: settings.prefix,
+            concurrency: settings.concurrency,
+            shutdown_timeout: settings.shutdown_timeout.as_secs_f64(),
+            storage: None,
+            store_per_channel: false,
+            channel: HashMap::new(),
+            cooldowns: CooldownConfig::default(),
+            banphrase: BanphraseConfig::default(),
+            permissions: HashMap::new(),
+            commands: HashMap::new(),
+        }
+    }
+}
+
+/// Longest duration config accepts, in seconds. Anything longer is surely a mistake, and would eventually
+/// not fit into `Duration`.
+const MAX_DURATION: f64 = 24.0 * 60.0 * 60.0;
+
+fn check_duration(problems: &mut Vec<String>, what: &str, seconds: f64) {
+    if !seconds.is_finite() || seconds <= 0.0 || seconds > MAX_DURATION {
+        problems.push(format!(
+            "{} should be a positive number of seconds, no more than {}, got {}",
+            what, MAX_DURATION, seconds
+        ));
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, what: &str, url: &str, schemes: &[&str]) {
+    match Url::parse(url) {
+        Ok(url) if schemes.contains(&url.scheme()) => {}
+        Ok(url) => problems.push(format!(
+            "{} has unsupported scheme '{}', expected one of: {}",
+            what,
+            url.scheme(),
+            schemes.join(", ")
+        )),
+        Err(err) => problems.push(format!("{} is not a valid URL: {}", what, err)),
+    }
+}
+
+impl Config {
+    /// Loads configuration from a file. Format is chosen based on extension: `.yaml`/`.yml` for YAML,
+    /// anything else is treated as TOML.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Config::from_yaml(&contents),
+            _ => Config::from_toml(&contents),
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str(contents).map_err(|err| ConfigError::Parse(err.to_string()))
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Config, ConfigError> {
+        serde_yaml::from_str(contents).map_err(|err| ConfigError::Parse(err.to_string()))
+    }
+
+    /// Checks that configuration makes sense for a given set of commands.
+    pub fn validate<T: 'static + Send + Sync>(&self, commands: &Commands<T>) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        check_url(&mut problems, "url", &self.url, &["ws", "wss", "irc", "ircs"]);
+        if !self.banphrase.url.is_empty() {
+            check_url(&mut problems, "banphrase.url", &self.banphrase.url, &["http", "https"]);
+        }
+        check_duration(&mut problems, "banphrase.timeout", self.banphrase.timeout);
+        check_duration(&mut problems, "banphrase.cache_ttl", self.banphrase.cache_ttl);
+        let mut policies = std::iter::once(self.banphrase.on_failure)
+            .chain(self.channel.values().filter_map(|config| config.banphrase_on_failure));
+        if self.banphrase.rules.is_none() && policies.any(|policy| policy == FailurePolicy::Fallback) {
+            problems.push("banphrase.rules are required to fall back to them".to_string());
+        }
+
+        if self.channels.is_empty() {
+            problems.push("no channels to join".to_string());
+        }
+        for channel in self.channels.iter() {
+            if channel.trim().trim_start_matches('#').is_empty() {
+                problems.push("channel name should not be empty".to_string());
+            }
+        }
+
+        if self.prefix.is_empty() {
+            problems.push("prefix should not be empty".to_string());
+        }
+        for (channel, config) in self.channel.iter() {
+            if let Some(prefix) = &config.prefix {
+                if prefix.is_empty() {
+                    problems.push(format!("channel.{}.prefix should not be empty", channel));
+                }
+            }
+            if let Some(url) = &config.banphrase_url {
+                if !url.is_empty() {
+                    check_url(
+                        &mut problems,
+                        &format!("channel.{}.banphrase_url", channel),
+                        url,
+                        &["http", "https"],
+                    );
+                }
+            }
+        }
+
+        if self.concurrency == 0 {
+            problems.push("concurrency should be positive".to_string());
+        }
+
+        check_duration(&mut problems, "shutdown_timeout", self.shutdown_timeout);
+        check_duration(&mut problems, "cooldowns.channel", self.cooldowns.channel);
+        check_duration(&mut problems, "cooldowns.history_ttl", self.cooldowns.history_ttl);
+
+        let mut names: Vec<&String> = self.commands.keys().collect();
+        names.sort_unstable();
+        for name in names {
+            let config = &self.commands[name];
+            if !commands.contains_key(name) {
+                problems.push(format!("commands.{}: no such command", name));
+                continue;
+            }
+            if let Some(cooldown) = config.cooldown {
+                check_duration(&mut problems, &format!("commands.{}.cooldown", name), cooldown);
+            }
+            if let Some(cooldown) = config.user_cooldown {
+                check_duration(&mut problems, &format!("commands.{}.user_cooldown", name), cooldown);
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&self.url).expect("url should have been validated")
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            channels: self.channels.clone(),
+            prefix: self.prefix.clone(),
+            channel_prefixes: self
+                .channel
+                .iter()
+                .filter_map(|(channel, config)| config.prefix.clone().map(|prefix| (channel.clone(), prefix)))
+                .collect(),
+            channel_cooldown: Duration::from_secs_f64(self.cooldowns.channel),
+            history_ttl: Duration::from_secs_f64(self.cooldowns.history_ttl),
+            banphrase_api_url: self.banphrase.url.clone(),
+            concurrency: self.concurrency,
+            shutdown_timeout: Duration::from_secs_f64(self.shutdown_timeout),
+        }
+    }
+
+    /// Creates banphrase checker which consults local rules (if any) and pajbot instance of a channel.
+    pub fn banphrase(&self) -> Result<CompositeChecker, ConfigError> {
+        let rules = match &self.banphrase.rules {
+            Some(path) => {
+                let rules = LocalRules::load(path).map_err(|err| match err {
+                    RulesError::Io(err) => ConfigError::Io(path.clone(), err),
+                    err => ConfigError::Parse(format!("{}: {}", path.display(), err)),
+                })?;
+                info!("Loaded {} banphrase rules from {}", rules.len(), path.display());
+                Some(Arc::new(rules))
+            }
+            None => None,
+        };
+
+        let pajbot = |url: &str| -> Box<dyn BanphraseChecker> {
+            if url.is_empty() {
+                return Box::new(CompositeChecker::new(Vec::new()));
+            }
+            let pajbot = CachedChecker::new(PajbotAPI::new(url.to_string()))
+                .capacity(self.banphrase.cache_size)
+                .ttl(Duration::from_secs_f64(self.banphrase.cache_ttl));
+            let mut checker = GuardedChecker::new(pajbot)
+                .timeout(Duration::from_secs_f64(self.banphrase.timeout))
+                .retries(self.banphrase.retries)
+                .policy(self.banphrase.on_failure);
+            for (channel, config) in self.channel.iter() {
+                if let Some(policy) = config.banphrase_on_failure {
+                    checker = checker.channel_policy(channel, policy);
+                }
+            }
+            if let Some(rules) = &rules {
+                checker = checker.fallback(rules.clone());
+            }
+            Box::new(checker)
+        };
+
+        let mut per_channel = PerChannelChecker::new().fallback(pajbot(&self.banphrase.url));
+        for (channel, config) in self.channel.iter() {
+            if let Some(url) = &config.banphrase_url {
+                per_channel = per_channel.channel(channel, pajbot(url));
+            }
+        }
+
+        let mut checkers: Vec<Box<dyn BanphraseChecker>> = vec![Box::new(per_channel)];
+        if let Some(rules) = rules {
+            checkers.push(Box::new(rules));
+        }
+
+        Ok(CompositeChecker::new(checkers))
+    }
+
+    pub fn permissions(&self) -> PermissionList {
+        PermissionList::new(self.permissions.clone())
+    }
+
+    /// Removes disabled commands and applies overrides to the rest.
+    pub fn apply_overrides<T: 'static + Send + Sync>(&self, commands: Commands<T>) -> Commands<T> {
+        commands
+            .into_iter()
+            .filter_map(|(name, command)| match self.commands.get(&name) {
+                Some(config) if !config.enabled => None,
+                Some(config) => {
+                    let overridden: ShareableExecutableCommand<T> = Box::new(Overridden::new(command, config));
+                    Some((name, overridden))
+                }
+                None => Some((name, command)),
+            })
+            .collect()
+    }
+}
+
+/// Command with cooldowns and permission level taken from config.
+struct Overridden<T> {
+    inner: ShareableExecutableCommand<T>,
+    command_cooldown: Option<Duration>,
+    user_cooldown: Option<Duration>,
+    level: PermissionLevel,
+    overflow: Overflow,
+}
+
+impl<T: 'static + Send + Sync> Overridden<T> {
+    fn new(inner: ShareableExecutableCommand<T>, config: &CommandConfig) -> Overridden<T> {
+        let cooldown = inner.cooldown();
+        Overridden {
+            command_cooldown: config.cooldown.map(Duration::from_secs_f64).or(cooldown.command),
+            user_cooldown: config.user_cooldown.map(Duration::from_secs_f64).or(cooldown.user),
+            level: config.level.unwrap_or_else(|| inner.level()),
+            overflow: config.overflow.unwrap_or_else(|| inner.overflow()),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: 'static + Send + Sync> ExecutableCommand<T> for Overridden<T> {
+    async fn execute<'a>(
+        &self,
+        command: &'a str,
+        message: irc::Message<'a>,
+        event: &'a twitch::Event,
+        state: &BotState<T>,
+    ) -> ExecutionOutcome {
+        self.inner.execute(command, message, event, state).await
+    }
+
+    fn help(&self) -> String {
+        self.inner.help()
+    }
+
+    fn cooldown(&self) -> CommandCooldown {
+        CommandCooldown {
+            command: self.command_cooldown,
+            user: self.user_cooldown,
+        }
+    }
+
+    fn level(&self) -> PermissionLevel {
+        self.level
+    }
+
+    fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::commands::{commands, MyState};
+
+    const EXAMPLE: &str = include_str!("../config.example.toml");
+
+    #[test]
+    fn test_example_is_valid() {
+        match Config::from_toml(EXAMPLE) {
+            Ok(config) => match config.validate(&commands(&config)) {
+                Ok(()) => assert!(true),
+                Err(err) => assert!(false, "example config is invalid: {}", err),
+            },
+            Err(err) => assert!(false, "failed to parse example config: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_example_rules_are_valid() {
+        match LocalRules::from_toml(include_str!("../banphrases.example.toml")) {
+            Ok(rules) => assert_eq!(rules.len(), 3),
+            Err(err) => assert!(false, "example rules are invalid: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_banphrase_settings_are_validated() {
+        let config = Config::from_toml(
+            r#"
+channels = ["forsen", "pajlada"]
+
+[banphrase]
+url = ""
+
+[channel.forsen]
+banphrase_url = "ftp://forsen.tv"
+banphrase_on_failure = "fallback"
+
+[channel.pajlada]
+banphrase_url = ""
+"#,
+        )
+        .expect("failed to parse toml");
+
+        match config.validate(&commands(&config)) {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 2, "wrong problems: {:?}", problems),
+            _ => assert!(false, "config should be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_yaml_is_supported() {
+        let config = Config::from_yaml(
+            "\
+channels: [forsen]
+channel:
+  forsen:
+    prefix: '!'
+permissions:
+  modelflat: admin
+",
+        )
+        .expect("failed to parse yaml");
+
+        let settings = config.settings();
+        assert_eq!(settings.channels, vec!["forsen".to_string()]);
+        assert_eq!(settings.channel_prefixes.get("forsen"), Some(&"!".to_string()));
+        assert!(config.permissions().get("modelflat").permits(PermissionLevel::Admin));
+    }
+
+    #[test]
+    fn test_all_problems_are_reported() {
+        let config = Config::from_toml(
+            r#"
+url = "http://irc.chat.twitch.tv"
+channels = []
+
+[cooldowns]
+channel = -1
+
+[commands.nonexistent]
+enabled = false
+"#,
+        )
+        .expect("failed to parse toml");
+
+        match config.validate(&commands(&config)) {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 4, "wrong problems: {:?}", problems),
+            _ => assert!(false, "config should be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_durations_are_bounded() {
+        let config = Config::from_toml(
+            r#"
+channels = ["forsen"]
+shutdown_timeout = 1e300
+
+[commands.echo]
+user_cooldown = 86401
+"#,
+        )
+        .expect("failed to parse toml");
+
+        match config.validate(&commands(&config)) {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 2, "wrong problems: {:?}", problems),
+            _ => assert!(false, "config should be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        match Config::from_toml("chanels = [\"forsen\"]") {
+            Err(ConfigError::Parse(_)) => assert!(true),
+            _ => assert!(false, "misspelled field should be reported"),
+        }
+    }
+
+    #[test]
+    fn test_overrides_are_applied() {
+        let config = Config::from_toml(
+            r#"
+[commands.echo]
+enabled = false
+
+[commands.help]
+user_cooldown = 10
+level = "admin"
+
+[commands.lua]
+overflow = "split"
+"#,
+        )
+        .expect("failed to parse toml");
+
+        let commands: Commands<MyState> = config.apply_overrides(commands(&config));
+
+        assert!(!commands.contains_key("echo"), "disabled command should be removed");
+
+        let help = commands.get("help").expect("help command is lost");
+        assert_eq!(help.cooldown().user, Some(Duration::from_secs(10)));
+        assert_eq!(help.cooldown().command, Some(Duration::from_secs(5)));
+        assert!(PermissionLevel::Admin.permits(help.level()) && help.level().permits(PermissionLevel::Admin));
+        assert_eq!(
+            help.overflow(),
+            Overflow::Split,
+            "overflow should be kept if not overridden"
+        );
+
+        assert_eq!(
+            commands.get("lua").expect("lua command is lost").overflow(),
+            Overflow::Split
+        );
+    }
+}
@@ -1,37 +1,157 @@
-use url::Url;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::Poll;
+
+use log::*;
 use structopt::StructOpt;
+use futures::pin_mut;
+use tokio::prelude::*;
+
+use bot::storage::FileStorage;
+use bot::transport::{TcpTransport, WebSocketTransport};
+use bot::Bot;
 
 mod commands;
-use commands::{commands, permissions, state};
+use commands::{commands, state};
+
+mod config;
+use config::{Config, ConfigError};
 
 #[derive(StructOpt)]
 #[structopt(about = "primitive twitch bot")]
 struct Opt {
 
-    /// Which channels should the bot join upon startup, comma-separated
+    /// Path to configuration file (TOML, or YAML if extension is .yaml/.yml)
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Which channels should the bot join upon startup in addition to ones in config, comma-separated
     #[structopt(long)]
-    channels: String,
+    channels: Option<String>,
 
 }
 
+fn load_config(opt: &Opt) -> Result<Config, ConfigError> {
+    let mut config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(channels) = &opt.channels {
+        config.channels.extend(channels.split_terminator(',').map(|s| s.to_string()));
+    }
+
+    config.validate(&commands(&config))?;
+
+    Ok(config)
+}
+
+/// Stream of SIGINT (Ctrl+C) and SIGTERM notifications.
+#[cfg(unix)]
+fn termination_signals() -> std::io::Result<impl Stream<Item = ()>> {
+    use tokio_net::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    Ok(tokio::stream::poll_fn(move |cx| match Pin::new(&mut interrupt).poll_next(cx) {
+        Poll::Pending => Pin::new(&mut terminate).poll_next(cx),
+        ready => ready,
+    }))
+}
+
+/// Stream of Ctrl+C notifications.
+#[cfg(not(unix))]
+fn termination_signals() -> std::io::Result<impl Stream<Item = ()>> {
+    tokio_net::signal::ctrl_c()
+}
+
 fn main() {
     let opt: Opt = Opt::from_args();
 
     env_logger::try_init().expect("Failed to initialize logger");
 
-    let url = Url::parse("wss://irc-ws.chat.twitch.tv:443").unwrap();
+    let config = match load_config(&opt) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let (username, password) = match (config.credentials.username.resolve(), config.credentials.token.resolve()) {
+        (Ok(username), Ok(password)) => (username, password),
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("Failed to get credentials: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let banphrase = match config.banphrase() {
+        Ok(banphrase) => banphrase,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let url = config.url();
+    let builder = match TcpTransport::from_url(&url) {
+        Some(transport) => Bot::builder().transport(transport),
+        None => Bot::builder().transport(WebSocketTransport::new(url)),
+    };
+
+    let builder = match &config.storage {
+        Some(directory) => match FileStorage::new(directory.clone()) {
+            Ok(storage) => builder.storage(storage),
+            Err(err) => {
+                eprintln!("Failed to open storage at {}: {}", directory.display(), err);
+                std::process::exit(2);
+            }
+        },
+        None => builder,
+    };
+
+    let bot = builder
+        .credentials(username, password)
+        .settings(config.settings())
+        .banphrase(banphrase)
+        .commands(config.apply_overrides(commands(&config)))
+        .permissions(config.permissions())
+        .data(state())
+        .build()
+        .expect("Failed to build bot");
+
+    let runtime = tokio::runtime::Builder::new()
+        .build()
+        .expect("Failed to create runtime");
 
-    let username = std::env::var("TWITCH_USERNAME").expect("twitch username");
+    let shutdown = bot.shutdown_handle();
+    runtime.spawn(async move {
+        let signals = match termination_signals() {
+            Ok(signals) => signals,
+            Err(err) => {
+                error!("Failed to set up signal handling: {}", err);
+                return;
+            }
+        };
+        pin_mut!(signals);
 
-    let password = std::env::var("TWITCH_OAUTH_TOKEN").expect("twitch oauth token");
+        if signals.next().await.is_some() {
+            info!("Received termination signal, shutting down (repeat to exit immediately)...");
+            shutdown.shutdown();
+        }
+        if signals.next().await.is_some() {
+            warn!("Received termination signal again, exiting immediately");
+            std::process::exit(130);
+        }
+    });
 
-    bot::run(
-        url,
-        username,
-        password,
-        opt.channels.split_terminator(',').map(|s| s.to_string()).collect(),
-        state(),
-        commands(),
-        permissions(),
-    );
+    match runtime.block_on(bot) {
+        Ok(()) => info!("Bot has stopped"),
+        Err(err) => {
+            eprintln!("Bot has stopped: {}", err);
+            std::process::exit(1);
+        }
+    }
 }
@@ -1,32 +1,1074 @@
-use std::future::Future;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use reqwest::{Client, Response};
+use async_trait::async_trait;
+use log::*;
+use regex::{Regex, RegexBuilder};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::state::normalize_channel_name;
+
+pub type BanphraseError = Box<dyn Error + Send + Sync>;
+
+/// Decides whether a message can be sent to a channel.
+#[async_trait]
+pub trait BanphraseChecker: Send + Sync {
+    /// Returns `true` if message is banned in a channel.
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError>;
+}
+
+#[async_trait]
+impl<C: BanphraseChecker + ?Sized> BanphraseChecker for Box<C> {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        (**self).is_banned(channel, message).await
+    }
+}
+
+#[async_trait]
+impl<C: BanphraseChecker + ?Sized> BanphraseChecker for Arc<C> {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        (**self).is_banned(channel, message).await
+    }
+}
 
 #[derive(Serialize)]
-pub struct BanphraseRequest {
-    message: String,
+struct BanphraseRequest<'a> {
+    message: &'a str,
 }
 
 #[derive(Deserialize)]
-pub struct BanphraseResponse {
-    pub banned: bool,
+struct BanphraseResponse {
+    banned: bool,
 }
 
-pub struct BanphraseAPI {
+/// Checker which consults banphrase API of a pajbot instance.
+pub struct PajbotAPI {
     session: Client,
     url: String,
 }
 
-impl BanphraseAPI {
-    pub fn new(url: String) -> BanphraseAPI {
-        BanphraseAPI {
+impl PajbotAPI {
+    pub fn new(url: String) -> PajbotAPI {
+        PajbotAPI {
             session: Client::new(),
             url,
         }
     }
+}
+
+#[async_trait]
+impl BanphraseChecker for PajbotAPI {
+    async fn is_banned(&self, _: &str, message: &str) -> Result<bool, BanphraseError> {
+        let response = self
+            .session
+            .post(&self.url)
+            .json(&BanphraseRequest { message })
+            .send()
+            .await?
+            .error_for_status()?;
+        match response.json::<BanphraseResponse>().await {
+            Ok(response) => Ok(response.banned),
+            Err(err) => Err(format!("unexpected response from {}: {}", self.url, err).into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Pattern(String, regex::Error),
+}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            RulesError::Io(err) => f.write_fmt(format_args!("cannot read rules: {}", err)),
+            RulesError::Parse(err) => f.write_fmt(format_args!("cannot parse rules: {}", err)),
+            RulesError::Pattern(pattern, err) => f.write_fmt(format_args!("invalid pattern '{}': {}", pattern, err)),
+        }
+    }
+}
+
+impl Error for RulesError {}
+
+/// How rule pattern is matched against a message.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    /// Pattern occurs anywhere in the message.
+    Substring,
+    /// Pattern occurs in the message as a separate word (or words).
+    Word,
+    /// Pattern is a regular expression.
+    Regex,
+}
+
+impl Default for RuleKind {
+    fn default() -> RuleKind {
+        RuleKind::Substring
+    }
+}
+
+/// Banned phrase, as written in rules file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleDefinition {
+    pub pattern: String,
+    #[serde(default)]
+    pub kind: RuleKind,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    /// Channels this rule applies to. Empty means all channels.
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleDefinition>,
+}
+
+struct Rule {
+    regex: Regex,
+    channels: HashSet<String>,
+}
+
+impl Rule {
+    fn applies_to(&self, channel: &str) -> bool {
+        self.channels.is_empty() || self.channels.contains(channel)
+    }
+}
+
+/// Brings text to the form rules are matched against: compatibility-normalized (so that e.g. fullwidth
+/// or stylized letters match their plain counterparts), with invisible characters removed.
+fn normalize(text: &str) -> String {
+    text.nfkc().filter(|c| !is_invisible(*c)).collect()
+}
+
+/// Characters which are not displayed, and therefore can be used to sneak a banned phrase through.
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\u{00ad}' | '\u{034f}' | '\u{061c}' | '\u{180e}' | '\u{feff}' => true,
+        '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{206f}' => true,
+        // tags, which are also used by us to avoid duplicates
+        '\u{e0000}'..='\u{e007f}' => true,
+        _ => false,
+    }
+}
+
+/// Checker which matches messages against a local list of banned phrases.
+pub struct LocalRules {
+    rules: Vec<Rule>,
+}
+
+impl LocalRules {
+    pub fn new(definitions: Vec<RuleDefinition>) -> Result<LocalRules, RulesError> {
+        let rules = definitions
+            .into_iter()
+            .map(|definition| {
+                let pattern = match definition.kind {
+                    RuleKind::Substring => regex::escape(&normalize(&definition.pattern)),
+                    RuleKind::Word => format!(r"(?:^|\W){}(?:\W|$)", regex::escape(&normalize(&definition.pattern))),
+                    RuleKind::Regex => definition.pattern.clone(),
+                };
+                let channels = definition.channels.iter().map(|c| normalize_channel_name(c)).collect();
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(!definition.case_sensitive)
+                    .build()
+                    .map_err(|err| RulesError::Pattern(definition.pattern, err))?;
+                Ok(Rule { regex, channels })
+            })
+            .collect::<Result<Vec<Rule>, RulesError>>()?;
+        Ok(LocalRules { rules })
+    }
+
+    /// Parses rules written in TOML as an array of tables named `rule`.
+    pub fn from_toml(contents: &str) -> Result<LocalRules, RulesError> {
+        let file: RulesFile = toml::from_str(contents).map_err(RulesError::Parse)?;
+        LocalRules::new(file.rule)
+    }
+
+    pub fn load(path: &Path) -> Result<LocalRules, RulesError> {
+        LocalRules::from_toml(&std::fs::read_to_string(path).map_err(RulesError::Io)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns `true` if any of the rules applicable to a channel matches message.
+    pub fn matches(&self, channel: &str, message: &str) -> bool {
+        let message = normalize(message);
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(channel) && rule.regex.is_match(&message))
+    }
+}
+
+#[async_trait]
+impl BanphraseChecker for LocalRules {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        Ok(self.matches(channel, message))
+    }
+}
+
+/// Checker which consults several checkers at once.
+///
+/// Message is banned if any of the checkers says so, even if the others have failed. Otherwise,
+/// the first error (if any) is returned. Without checkers, nothing is banned.
+pub struct CompositeChecker {
+    checkers: Vec<Box<dyn BanphraseChecker>>,
+}
+
+impl CompositeChecker {
+    pub fn new(checkers: Vec<Box<dyn BanphraseChecker>>) -> CompositeChecker {
+        CompositeChecker { checkers }
+    }
+}
+
+#[async_trait]
+impl BanphraseChecker for CompositeChecker {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        let verdicts =
+            futures::future::join_all(self.checkers.iter().map(|checker| checker.is_banned(channel, message))).await;
+
+        let mut error = None;
+        for verdict in verdicts {
+            match verdict {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(err) => error = error.or(Some(err)),
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Checker which delegates to a checker set up for a channel, e.g. to channel's own pajbot instance.
+/// Channels without their own checker use the fallback one, if there is any.
+pub struct PerChannelChecker {
+    channels: HashMap<String, Box<dyn BanphraseChecker>>,
+    fallback: Option<Box<dyn BanphraseChecker>>,
+}
+
+impl PerChannelChecker {
+    pub fn new() -> PerChannelChecker {
+        PerChannelChecker {
+            channels: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    pub fn channel(mut self, channel: &str, checker: impl BanphraseChecker + 'static) -> Self {
+        self.channels.insert(normalize_channel_name(channel), Box::new(checker));
+        self
+    }
+
+    /// Sets checker for channels which don't have their own.
+    pub fn fallback(mut self, checker: impl BanphraseChecker + 'static) -> Self {
+        self.fallback = Some(Box::new(checker));
+        self
+    }
+}
+
+impl Default for PerChannelChecker {
+    fn default() -> PerChannelChecker {
+        PerChannelChecker::new()
+    }
+}
+
+#[async_trait]
+impl BanphraseChecker for PerChannelChecker {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        match self.channels.get(channel).or_else(|| self.fallback.as_ref()) {
+            Some(checker) => checker.is_banned(channel, message).await,
+            None => Ok(false),
+        }
+    }
+}
+
+/// What to do with a message when banphrase checker fails to give a verdict.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Don't send the message.
+    Closed,
+    /// Send the message anyway.
+    Open,
+    /// Consult fallback checker (e.g. local rules), and don't send the message if there is none.
+    Fallback,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+}
+
+/// Counters of [`GuardedChecker`] failures and decisions made because of them.
+#[derive(Debug, Default)]
+pub struct GuardStats {
+    /// Failed attempts, including timeouts.
+    pub failures: AtomicUsize,
+    /// Checks which were not attempted because circuit breaker was open.
+    pub short_circuited: AtomicUsize,
+    /// Messages sent without a verdict.
+    pub failed_open: AtomicUsize,
+    /// Messages not sent because there was no verdict.
+    pub failed_closed: AtomicUsize,
+    /// Messages checked by fallback checker.
+    pub fell_back: AtomicUsize,
+}
+
+struct BreakerState {
+    failures: usize,
+    open_until: Option<Instant>,
+}
+
+/// Stops requests to a checker after several consecutive failures. Once cooldown passes, a single request
+/// is let through to find out whether checker has recovered.
+struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: usize, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<BreakerState> {
+        self.state
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+    }
+
+    fn allows_request(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.lock();
+        match state.open_until {
+            Some(open_until) if open_until > now => false,
+            Some(_) => {
+                // let only this request through until we know the outcome
+                state.open_until = Some(now + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.lock();
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    /// Returns `true` if circuit got open because of this failure.
+    fn record_failure(&self) -> bool {
+        let mut state = self.lock();
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_CHECK_RETRIES: usize = 1;
+
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+const BREAKER_THRESHOLD: usize = 5;
+
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Checker which protects against a failing (usually remote) checker: limits time of a check, retries
+/// failed checks, stops checking for a while if checker keeps failing, and decides what to do with a message
+/// when there is no verdict according to a (per-channel) failure policy.
+pub struct GuardedChecker<C> {
+    inner: C,
+    timeout: Duration,
+    retries: usize,
+    breaker: CircuitBreaker,
+    policy: FailurePolicy,
+    channel_policies: HashMap<String, FailurePolicy>,
+    fallback: Option<Box<dyn BanphraseChecker>>,
+    stats: Arc<GuardStats>,
+}
+
+impl<C: BanphraseChecker> GuardedChecker<C> {
+    pub fn new(inner: C) -> GuardedChecker<C> {
+        GuardedChecker {
+            inner,
+            timeout: DEFAULT_CHECK_TIMEOUT,
+            retries: DEFAULT_CHECK_RETRIES,
+            breaker: CircuitBreaker::new(BREAKER_THRESHOLD, BREAKER_COOLDOWN),
+            policy: FailurePolicy::default(),
+            channel_policies: HashMap::new(),
+            fallback: None,
+            stats: Arc::new(GuardStats::default()),
+        }
+    }
+
+    /// Sets how long a single attempt can take.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a failed check is retried.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets after how many consecutive failures checker is left alone, and for how long.
+    pub fn circuit_breaker(mut self, threshold: usize, cooldown: Duration) -> Self {
+        self.breaker = CircuitBreaker::new(threshold.max(1), cooldown);
+        self
+    }
+
+    /// Sets failure policy for channels which don't have their own.
+    pub fn policy(mut self, policy: FailurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn channel_policy(mut self, channel: &str, policy: FailurePolicy) -> Self {
+        self.channel_policies.insert(normalize_channel_name(channel), policy);
+        self
+    }
+
+    /// Sets checker used by `FailurePolicy::Fallback`.
+    pub fn fallback(mut self, checker: impl BanphraseChecker + 'static) -> Self {
+        self.fallback = Some(Box::new(checker));
+        self
+    }
+
+    pub fn stats(&self) -> Arc<GuardStats> {
+        self.stats.clone()
+    }
+
+    async fn check(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        if !self.breaker.allows_request() {
+            self.stats.short_circuited.fetch_add(1, Ordering::Relaxed);
+            return Err("checker is unavailable (circuit breaker is open)".into());
+        }
+
+        let mut attempt = 0;
+        loop {
+            let error: BanphraseError =
+                match tokio::timer::Timeout::new(self.inner.is_banned(channel, message), self.timeout).await {
+                    Ok(Ok(banned)) => {
+                        self.breaker.record_success();
+                        return Ok(banned);
+                    }
+                    Ok(Err(err)) => err,
+                    Err(_) => format!("check timed out after {:?}", self.timeout).into(),
+                };
+
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+            if self.breaker.record_failure() {
+                warn!(
+                    "Banphrase checker keeps failing, not using it for {:?}",
+                    self.breaker.cooldown
+                );
+                return Err(error);
+            }
+            if attempt >= self.retries {
+                return Err(error);
+            }
+
+            attempt += 1;
+            info!(
+                "Banphrase check failed ({}), retrying ({}/{})",
+                error, attempt, self.retries
+            );
+            tokio::timer::delay_for(RETRY_DELAY).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<C: BanphraseChecker> BanphraseChecker for GuardedChecker<C> {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        let error = match self.check(channel, message).await {
+            Ok(banned) => return Ok(banned),
+            Err(err) => err,
+        };
+
+        let policy = self.channel_policies.get(channel).copied().unwrap_or(self.policy);
+        match (policy, &self.fallback) {
+            (FailurePolicy::Open, _) => {
+                let count = self.stats.failed_open.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Banphrase check failed in {} ({}) -- sending anyway ({} so far)",
+                    channel, error, count
+                );
+                Ok(false)
+            }
+            (FailurePolicy::Fallback, Some(fallback)) => {
+                let count = self.stats.fell_back.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Banphrase check failed in {} ({}) -- using fallback checker ({} so far)",
+                    channel, error, count
+                );
+                fallback.is_banned(channel, message).await
+            }
+            (FailurePolicy::Closed, _) | (FailurePolicy::Fallback, None) => {
+                let count = self.stats.failed_closed.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Banphrase check failed in {} ({}) -- not sending ({} so far)",
+                    channel, error, count
+                );
+                Err(error)
+            }
+        }
+    }
+}
+
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+struct CacheEntry {
+    banned: bool,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(String, String), CacheEntry>,
+    /// Keys by the time they were last used, to find the least recently used one.
+    usage: BTreeMap<u64, (String, String)>,
+    clock: u64,
+}
+
+impl CacheState {
+    fn get(&mut self, key: &(String, String)) -> Option<bool> {
+        let now = Instant::now();
+        let (banned, expires_at, last_used) = match self.entries.get(key) {
+            Some(entry) => (entry.banned, entry.expires_at, entry.last_used),
+            None => return None,
+        };
+
+        self.usage.remove(&last_used);
+        if expires_at <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        self.usage.insert(self.clock, key.clone());
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.last_used = self.clock;
+        }
+        Some(banned)
+    }
+
+    fn insert(&mut self, key: (String, String), banned: bool, ttl: Duration, capacity: usize) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.usage.remove(&entry.last_used);
+        }
+
+        while self.entries.len() >= capacity {
+            let oldest = match self.usage.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(evicted) = self.usage.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+
+        self.clock += 1;
+        self.usage.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                banned,
+                expires_at: Instant::now() + ttl,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+/// Checker which remembers verdicts of another checker for a while, so that repeated messages (e.g. output
+/// of `help`) don't have to be checked again. Only the least recently used `capacity` verdicts are kept.
+///
+/// Invisible tag characters (added to avoid duplicates and chat commands) are ignored, so a message is
+/// the same one with and without them.
+///
+/// Failures are not cached. Wrap checker in this one before wrapping it in [`GuardedChecker`], otherwise
+/// decisions made on failures will be cached too.
+pub struct CachedChecker<C> {
+    inner: C,
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl<C: BanphraseChecker> CachedChecker<C> {
+    pub fn new(inner: C) -> CachedChecker<C> {
+        CachedChecker {
+            inner,
+            capacity: DEFAULT_CACHE_CAPACITY,
+            ttl: DEFAULT_CACHE_TTL,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how long verdicts are remembered.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<CacheState> {
+        self.state
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+    }
+
+    /// Forgets all verdicts, e.g. when list of banned phrases is known to have changed.
+    pub fn invalidate(&self) {
+        *self.lock() = CacheState::default();
+    }
+
+    /// Forgets verdicts for a single channel.
+    pub fn invalidate_channel(&self, channel: &str) {
+        let mut state = self.lock();
+        let CacheState { entries, usage, .. } = &mut *state;
+        entries.retain(|(entry_channel, _), entry| {
+            if entry_channel == channel {
+                usage.remove(&entry.last_used);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl<C: BanphraseChecker> BanphraseChecker for CachedChecker<C> {
+    async fn is_banned(&self, channel: &str, message: &str) -> Result<bool, BanphraseError> {
+        let text = message
+            .chars()
+            .filter(|c| !('\u{e0000}'..='\u{e007f}').contains(c))
+            .collect();
+        let key = (channel.to_string(), text);
+        if let Some(banned) = self.lock().get(&key) {
+            trace!("Using cached banphrase verdict for {:?}", message);
+            return Ok(banned);
+        }
+
+        let banned = self.inner.is_banned(channel, message).await?;
+        self.lock().insert(key, banned, self.ttl, self.capacity);
+        Ok(banned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use futures::executor::block_on;
+
+    fn rule(pattern: &str, kind: RuleKind) -> RuleDefinition {
+        RuleDefinition {
+            pattern: pattern.to_string(),
+            kind,
+            case_sensitive: false,
+            channels: Vec::new(),
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl BanphraseChecker for Failing {
+        async fn is_banned(&self, _: &str, _: &str) -> Result<bool, BanphraseError> {
+            Err("unavailable".into())
+        }
+    }
+
+    /// Fails a given number of times, then says that nothing is banned.
+    struct Flaky {
+        failures: usize,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl BanphraseChecker for Flaky {
+        async fn is_banned(&self, _: &str, _: &str) -> Result<bool, BanphraseError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err("unavailable".into())
+            } else {
+                Ok(false)
+            }
+        }
+    }
+
+    struct Slow;
+
+    #[async_trait]
+    impl BanphraseChecker for Slow {
+        async fn is_banned(&self, _: &str, _: &str) -> Result<bool, BanphraseError> {
+            tokio::timer::delay_for(Duration::from_secs(10)).await;
+            Ok(false)
+        }
+    }
+
+    fn flaky(failures: usize) -> (Flaky, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (
+            Flaky {
+                failures,
+                calls: calls.clone(),
+            },
+            calls,
+        )
+    }
+
+    fn run<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new()
+            .build()
+            .expect("Failed to create runtime")
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_substring_rules_are_case_insensitive() {
+        let rules = LocalRules::new(vec![rule("forsen", RuleKind::Substring)]).unwrap();
+
+        assert!(rules.matches("test", "FoRsEnE"));
+        assert!(!rules.matches("test", "fors en"));
+    }
+
+    #[test]
+    fn test_case_sensitive_rules() {
+        let rules = LocalRules::new(vec![RuleDefinition {
+            case_sensitive: true,
+            ..rule("OMEGALUL", RuleKind::Substring)
+        }])
+        .unwrap();
+
+        assert!(rules.matches("test", "OMEGALUL"));
+        assert!(!rules.matches("test", "omegalul"));
+    }
+
+    #[test]
+    fn test_word_rules_respect_boundaries() {
+        let rules = LocalRules::new(vec![rule("ass", RuleKind::Word)]).unwrap();
+
+        assert!(rules.matches("test", "ass"));
+        assert!(rules.matches("test", "you are an ASS!"));
+        assert!(!rules.matches("test", "classic"));
+        assert!(!rules.matches("test", "assistant"));
+    }
+
+    #[test]
+    fn test_regex_rules() {
+        let rules = LocalRules::new(vec![rule(r"\bf+o+r+s+e+n+\b", RuleKind::Regex)]).unwrap();
+
+        assert!(rules.matches("test", "ffoorrsseenn"));
+        assert!(!rules.matches("test", "forsenE"));
+
+        match LocalRules::new(vec![rule("(unclosed", RuleKind::Regex)]) {
+            Err(RulesError::Pattern(..)) => assert!(true),
+            _ => assert!(false, "invalid regex should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_messages_are_normalized() {
+        let rules = LocalRules::new(vec![rule("forsen", RuleKind::Word)]).unwrap();
+
+        // fullwidth letters
+        assert!(rules.matches("test", "\u{ff46}\u{ff4f}\u{ff52}\u{ff53}\u{ff45}\u{ff4e}"));
+        // zero width space and soft hyphen inside
+        assert!(rules.matches("test", "for\u{200b}s\u{00ad}en"));
+        // our own duplicate-avoiding suffix
+        let mut message = "forsen".to_string();
+        crate::util::modify_message(&mut message, 0);
+        assert!(rules.matches("test", &message));
+    }
+
+    #[test]
+    fn test_rules_can_be_limited_to_channels() {
+        let rules = LocalRules::from_toml(
+            r##"
+[[rule]]
+pattern = "pajaS"
+
+[[rule]]
+pattern = "forsen"
+kind = "word"
+channels = ["#Pajlada"]
+"##,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert!(rules.matches("pajlada", "forsen"));
+        assert!(!rules.matches("forsen", "forsen"));
+        assert!(rules.matches("forsen", "pajaS"));
+    }
+
+    #[test]
+    fn test_composite_bans_if_any_checker_does() {
+        let checker = CompositeChecker::new(vec![
+            Box::new(Failing),
+            Box::new(LocalRules::new(vec![rule("forsen", RuleKind::Substring)]).unwrap()),
+        ]);
+
+        match block_on(checker.is_banned("test", "forsen")) {
+            Ok(true) => assert!(true),
+            other => assert!(false, "message should be banned, got {:?}", other),
+        }
+
+        match block_on(checker.is_banned("test", "hello")) {
+            Err(_) => assert!(true),
+            other => assert!(false, "failure should be reported, got {:?}", other),
+        }
+
+        match block_on(CompositeChecker::new(Vec::new()).is_banned("test", "forsen")) {
+            Ok(false) => assert!(true),
+            other => assert!(false, "nothing should be banned, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_per_channel_checkers_are_used() {
+        let checker = PerChannelChecker::new()
+            .channel(
+                "#Forsen",
+                LocalRules::new(vec![rule("pajaS", RuleKind::Substring)]).unwrap(),
+            )
+            .fallback(Failing);
+
+        match block_on(checker.is_banned("forsen", "pajaS")) {
+            Ok(true) => assert!(true),
+            other => assert!(false, "message should be banned, got {:?}", other),
+        }
+
+        match block_on(checker.is_banned("pajlada", "pajaS")) {
+            Err(_) => assert!(true),
+            other => assert!(false, "fallback checker should be used, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_failed_checks_are_retried() {
+        let (inner, calls) = flaky(2);
+        let checker = GuardedChecker::new(inner).retries(2);
+
+        match run(checker.is_banned("test", "hello")) {
+            Ok(false) => assert!(true),
+            other => assert!(false, "check should have succeeded, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(checker.stats().failures.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_failure_policies() {
+        let checker = GuardedChecker::new(Slow)
+            .timeout(Duration::from_millis(10))
+            .retries(0)
+            .circuit_breaker(100, Duration::from_secs(1))
+            .channel_policy("open", FailurePolicy::Open)
+            .channel_policy("fallback", FailurePolicy::Fallback)
+            .fallback(LocalRules::new(vec![rule("forsen", RuleKind::Substring)]).unwrap());
+
+        match run(checker.is_banned("closed", "hello")) {
+            Err(_) => assert!(true),
+            other => assert!(false, "message should not be allowed, got {:?}", other),
+        }
+        match run(checker.is_banned("open", "forsen")) {
+            Ok(false) => assert!(true),
+            other => assert!(false, "message should be allowed, got {:?}", other),
+        }
+        match run(checker.is_banned("fallback", "forsen")) {
+            Ok(true) => assert!(true),
+            other => assert!(false, "fallback should have banned message, got {:?}", other),
+        }
+        match run(checker.is_banned("fallback", "hello")) {
+            Ok(false) => assert!(true),
+            other => assert!(false, "fallback should have allowed message, got {:?}", other),
+        }
+
+        let stats = checker.stats();
+        assert_eq!(stats.failures.load(Ordering::SeqCst), 4);
+        assert_eq!(stats.failed_closed.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.failed_open.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.fell_back.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_circuit_breaker_stops_requests() {
+        let (inner, calls) = flaky(3);
+        let checker = GuardedChecker::new(inner)
+            .retries(5)
+            .circuit_breaker(3, Duration::from_millis(100));
+
+        // breaker opens after third failure, so there are no more retries
+        match run(checker.is_banned("test", "hello")) {
+            Err(_) => assert!(true),
+            other => assert!(false, "check should have failed, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        match run(checker.is_banned("test", "hello")) {
+            Err(_) => assert!(true),
+            other => assert!(false, "check should have been short-circuited, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(checker.stats().short_circuited.load(Ordering::SeqCst), 1);
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        match run(checker.is_banned("test", "hello")) {
+            Ok(false) => assert!(true),
+            other => assert!(false, "checker should have been tried again, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    /// Bans messages containing "forsen", counting calls.
+    struct Counting {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl BanphraseChecker for Counting {
+        async fn is_banned(&self, _: &str, message: &str) -> Result<bool, BanphraseError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(message.contains("forsen"))
+        }
+    }
+
+    fn cached() -> (CachedChecker<Counting>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (CachedChecker::new(Counting { calls: calls.clone() }), calls)
+    }
+
+    #[test]
+    fn test_verdicts_are_cached_per_channel() {
+        let (checker, calls) = cached();
+
+        for _ in 0..3 {
+            assert!(block_on(checker.is_banned("test", "forsen")).unwrap());
+            assert!(!block_on(checker.is_banned("test", "hello")).unwrap());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        block_on(checker.is_banned("other", "hello")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        checker.invalidate_channel("test");
+        assert_eq!(checker.len(), 1);
+        block_on(checker.is_banned("test", "hello")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        checker.invalidate();
+        assert!(checker.is_empty());
+    }
+
+    #[test]
+    fn test_tag_characters_are_ignored_by_cache() {
+        let (checker, calls) = cached();
+
+        for message in ["hello", "hello\u{e0000}", "hello\u{e0002}", "\u{e0000}hello"].iter() {
+            assert!(!block_on(checker.is_banned("test", message)).unwrap());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(checker.len(), 1);
+    }
+
+    #[test]
+    fn test_cached_verdicts_expire() {
+        let (checker, calls) = cached();
+        let checker = checker.ttl(Duration::from_millis(10));
+
+        block_on(checker.is_banned("test", "hello")).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        block_on(checker.is_banned("test", "hello")).unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_least_recently_used_verdicts_are_evicted() {
+        let (checker, calls) = cached();
+        let checker = checker.capacity(2);
+
+        block_on(checker.is_banned("test", "a")).unwrap();
+        block_on(checker.is_banned("test", "b")).unwrap();
+        block_on(checker.is_banned("test", "a")).unwrap();
+        // "b" is the least recently used one now
+        block_on(checker.is_banned("test", "c")).unwrap();
+        assert_eq!(checker.len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        block_on(checker.is_banned("test", "a")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        block_on(checker.is_banned("test", "b")).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_failures_are_not_cached() {
+        let checker = CachedChecker::new(Failing);
 
-    pub fn check(&self, message: String) -> impl Future<Output = reqwest::Result<Response>> {
-        self.session.post(&self.url).json(&BanphraseRequest { message }).send()
+        assert!(block_on(checker.is_banned("test", "hello")).is_err());
+        assert!(checker.is_empty());
     }
 }
@@ -0,0 +1,414 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc::channel;
+use futures::future::{BoxFuture, FutureExt};
+
+use log::*;
+
+use crate::banphrase::{BanphraseChecker, CachedChecker, GuardedChecker, PajbotAPI};
+use crate::connection::{self, ConnectionError};
+use crate::executor::{self, ExecutableCommand};
+use crate::messaging::{self, MessagingState, Queues};
+use crate::permissions::PermissionList;
+use crate::settings::Settings;
+use crate::shutdown::{shutdown_channel, ShutdownHandle};
+use crate::state::{normalize_channel_name, BotState, Commands};
+use crate::storage::{MemoryStorage, Storage};
+use crate::transport::{Transport, WebSocketTransport};
+
+#[derive(Debug)]
+pub enum BuildError {
+    MissingCredentials,
+    MissingData,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            BuildError::MissingCredentials => f.write_str("credentials are not set"),
+            BuildError::MissingData => f.write_str("user data is not set"),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Reason why bot has stopped abnormally.
+#[derive(Debug)]
+pub enum BotError {
+    Connection(ConnectionError),
+    Storage(io::Error),
+}
+
+impl Display for BotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            BotError::Connection(err) => f.write_fmt(format_args!("connection error: {}", err)),
+            BotError::Storage(err) => f.write_fmt(format_args!("failed to save state: {}", err)),
+        }
+    }
+}
+
+impl Error for BotError {}
+
+impl From<ConnectionError> for BotError {
+    fn from(err: ConnectionError) -> Self {
+        BotError::Connection(err)
+    }
+}
+
+/// Builder for `Bot`. Everything except credentials and user data has a default.
+pub struct BotBuilder<T: 'static + Send + Sync> {
+    credentials: Option<(String, String)>,
+    settings: Settings,
+    commands: Commands<T>,
+    permissions: Option<PermissionList>,
+    data: Option<T>,
+    banphrase: Option<Box<dyn BanphraseChecker>>,
+    transport: Option<Box<dyn Transport>>,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl<T: 'static + Send + Sync> BotBuilder<T> {
+    fn new() -> BotBuilder<T> {
+        BotBuilder {
+            credentials: None,
+            settings: Settings::default(),
+            commands: Commands::new(),
+            permissions: None,
+            data: None,
+            banphrase: None,
+            transport: None,
+            storage: None,
+        }
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Replaces settings. Channels added so far are kept.
+    pub fn settings(mut self, settings: Settings) -> Self {
+        let channels = std::mem::replace(&mut self.settings.channels, Vec::new());
+        self.settings = settings;
+        self.settings.channels.extend(channels);
+        self
+    }
+
+    /// Adds a channel to join upon startup.
+    pub fn channel(mut self, channel: impl Into<String>) -> Self {
+        self.settings.channels.push(channel.into());
+        self
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.settings.prefix = prefix.into();
+        self
+    }
+
+    pub fn command(
+        mut self,
+        name: impl Into<String>,
+        command: impl ExecutableCommand<T> + 'static + Send + Sync,
+    ) -> Self {
+        self.commands.insert(name.into(), Box::new(command));
+        self
+    }
+
+    pub fn commands(mut self, commands: Commands<T>) -> Self {
+        self.commands.extend(commands);
+        self
+    }
+
+    pub fn permissions(mut self, permissions: PermissionList) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+
+    pub fn data(mut self, data: T) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Sets banphrase checker. By default, pajbot API at `Settings::banphrase_api_url` is used, with default
+    /// timeout, retries and verdict cache, not sending messages it fails to check.
+    pub fn banphrase(mut self, banphrase: impl BanphraseChecker + 'static) -> Self {
+        self.banphrase = Some(Box::new(banphrase));
+        self
+    }
+
+    /// Sets transport. By default, Twitch is connected to via websocket.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    /// Sets storage. By default, nothing is persisted.
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    pub fn build(self) -> Result<Bot<T>, BuildError> {
+        let (username, password) = self.credentials.ok_or(BuildError::MissingCredentials)?;
+        let data = self.data.ok_or(BuildError::MissingData)?;
+
+        let mut settings = self.settings;
+        settings.channels = settings.channels.iter().map(|c| normalize_channel_name(c)).collect();
+        settings.channels.sort();
+        settings.channels.dedup();
+
+        let concurrency = settings.concurrency;
+        let banphrase = self.banphrase.unwrap_or_else(|| {
+            let pajbot = PajbotAPI::new(settings.banphrase_api_url.clone());
+            Box::new(GuardedChecker::new(CachedChecker::new(pajbot)))
+        });
+        let transport = self
+            .transport
+            .unwrap_or_else(|| Box::new(WebSocketTransport::default()));
+        let storage = self.storage.unwrap_or_else(|| Arc::new(MemoryStorage::new()));
+        let permissions = self
+            .permissions
+            .unwrap_or_else(|| PermissionList::new(Default::default()));
+
+        let (tx_line, rx_line) = channel(1024);
+        let (tx_command, rx_command) = channel(1024);
+        let (tx_message, rx_message) = channel(1024);
+        let (tx_channel, rx_channel) = channel(1024);
+
+        let messaging_state = Arc::new(MessagingState::new(
+            &settings.channels,
+            settings.channel_cooldown,
+            settings.history_ttl,
+            banphrase,
+        ));
+
+        let bot_state = Arc::new(BotState::new(
+            username,
+            &settings,
+            self.commands,
+            permissions,
+            data,
+            storage,
+            tx_channel,
+        ));
+
+        let (shutdown, signal) = shutdown_channel(settings.shutdown_timeout);
+
+        let state = bot_state.clone();
+        let future = async move {
+            // Message sending loop
+            tokio::spawn(messaging::sender_event_loop(
+                rx_message,
+                tx_line.clone(),
+                messaging_state.clone(),
+            ));
+
+            // Command handling loop
+            tokio::spawn(executor::event_loop(
+                rx_command,
+                tx_message.clone(),
+                state.clone(),
+                concurrency,
+            ));
+
+            // Channel membership loop
+            tokio::spawn(messaging::membership_event_loop(
+                rx_channel,
+                tx_line.clone(),
+                tx_message.clone(),
+                state.clone(),
+                messaging_state.clone(),
+                signal.clone(),
+            ));
+
+            // Main loop
+            let queues = Queues {
+                tx_line,
+                tx_message,
+                tx_command,
+            };
+            let result = connection::supervisor(
+                &*transport,
+                password,
+                rx_line,
+                queues,
+                state.clone(),
+                messaging_state,
+                signal,
+            )
+            .await;
+
+            info!("Saving state...");
+            state.storage.flush().map_err(BotError::Storage)?;
+
+            result.map_err(BotError::from)
+        };
+
+        Ok(Bot {
+            state: bot_state,
+            shutdown,
+            future: future.boxed(),
+        })
+    }
+}
+
+/// A configured bot. It runs when awaited, until either shutdown is requested or
+/// the server rejects our credentials.
+///
+/// On shutdown, commands and messages which are already queued are processed (within
+/// `Settings::shutdown_timeout`), channels are left and state is flushed to storage.
+///
+/// Bot should be awaited within tokio runtime.
+pub struct Bot<T: 'static + Send + Sync> {
+    state: Arc<BotState<T>>,
+    shutdown: ShutdownHandle,
+    future: BoxFuture<'static, Result<(), BotError>>,
+}
+
+impl<T: 'static + Send + Sync> Bot<T> {
+    pub fn builder() -> BotBuilder<T> {
+        BotBuilder::new()
+    }
+
+    pub fn state(&self) -> Arc<BotState<T>> {
+        self.state.clone()
+    }
+
+    /// Returns a handle which can be used to stop the bot once it is being awaited.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Asks the bot to stop.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown()
+    }
+}
+
+impl<T: 'static + Send + Sync> Future for Bot<T> {
+    type Output = Result<(), BotError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().future.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::banphrase::BanphraseError;
+    use crate::executor::{CommandCooldown, ExecutionOutcome};
+    use crate::irc;
+    use crate::permissions::PermissionLevel;
+    use crate::transport::{MemoryConnection, MemoryTransport};
+    use crate::twitch;
+
+    struct NothingIsBanned;
+
+    #[async_trait]
+    impl BanphraseChecker for NothingIsBanned {
+        async fn is_banned(&self, _: &str, _: &str) -> Result<bool, BanphraseError> {
+            Ok(false)
+        }
+    }
+
+    struct Ping;
+
+    #[async_trait]
+    impl ExecutableCommand<()> for Ping {
+        async fn execute<'a>(
+            &self,
+            _: &'a str,
+            message: irc::Message<'a>,
+            _: &'a twitch::Event,
+            _: &BotState<()>,
+        ) -> ExecutionOutcome {
+            ExecutionOutcome::success(
+                message.first_arg_as_channel_name().unwrap().to_string(),
+                "pong".to_string(),
+            )
+        }
+
+        fn help(&self) -> String {
+            "ping -- pongs".to_string()
+        }
+
+        fn cooldown(&self) -> CommandCooldown {
+            CommandCooldown {
+                command: Some(Duration::from_millis(1)),
+                user: None,
+            }
+        }
+
+        fn level(&self) -> PermissionLevel {
+            PermissionLevel::User
+        }
+    }
+
+    async fn next_line(connection: &mut MemoryConnection) -> Option<String> {
+        tokio::timer::Timeout::new(connection.recv(), Duration::from_secs(5))
+            .await
+            .expect("no line received in time")
+    }
+
+    #[test]
+    fn test_command_is_answered_and_bot_shuts_down() {
+        let runtime = tokio::runtime::Builder::new()
+            .build()
+            .expect("Failed to create runtime");
+
+        let (transport, mut server) = MemoryTransport::pair();
+
+        let bot = Bot::builder()
+            .credentials("bot", "token")
+            .channel("#Test")
+            .command("ping", Ping)
+            .banphrase(NothingIsBanned)
+            .transport(transport)
+            .data(())
+            .build()
+            .expect("Failed to build bot");
+        let shutdown = bot.shutdown_handle();
+
+        let server = async move {
+            let mut connection = server.accept().await.expect("bot didn't connect");
+
+            assert_eq!(next_line(&mut connection).await.as_deref(), Some("PASS oauth:token"));
+            assert_eq!(next_line(&mut connection).await.as_deref(), Some("NICK bot"));
+            match next_line(&mut connection).await {
+                Some(line) => assert!(line.starts_with("CAP REQ"), "unexpected line: {}", line),
+                None => assert!(false, "connection is closed"),
+            }
+
+            connection.send(":tmi.twitch.tv 001 bot :Welcome, GLHF!");
+            assert_eq!(next_line(&mut connection).await.as_deref(), Some("JOIN #test"));
+
+            connection.send(":user!user@user.tmi.twitch.tv PRIVMSG #test :>> ping");
+            assert_eq!(next_line(&mut connection).await.as_deref(), Some("PRIVMSG #test :pong"));
+
+            shutdown.shutdown();
+            assert_eq!(next_line(&mut connection).await.as_deref(), Some("PART #test"));
+            assert_eq!(next_line(&mut connection).await, None);
+        };
+
+        let (result, _) = runtime.block_on(futures::future::join(bot, server));
+        match result {
+            Ok(()) => assert!(true),
+            Err(err) => assert!(false, "bot has stopped with error: {}", err),
+        }
+    }
+}
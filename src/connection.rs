@@ -0,0 +1,454 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc::Receiver;
+use futures::future::{select, Either};
+use futures::{pin_mut, SinkExt, StreamExt};
+
+use log::*;
+
+use crate::irc;
+use crate::messaging::{self, MessagingState, Queues, JOIN_LIMIT};
+use crate::ratelimit::TokenBucket;
+use crate::shutdown::ShutdownSignal;
+use crate::state::BotState;
+use crate::transport::{LineSink, LineStream, Transport};
+
+/// How long the connection can stay silent before we check it with a PING.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+
+/// How long we wait for any response to our PING before considering connection dead.
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we wait for the server to either welcome us or reject our credentials.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why we stopped talking to the server.
+enum Outcome {
+    Lost(ConnectionError),
+    QueueClosed,
+    Shutdown,
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Socket(tungstenite::Error),
+    Io(std::io::Error),
+    LoginFailed(String),
+    Timeout,
+    Closed,
+    Reconnect,
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ConnectionError::Socket(err) => f.write_fmt(format_args!("socket error: {}", err)),
+            ConnectionError::Io(err) => f.write_fmt(format_args!("I/O error: {}", err)),
+            ConnectionError::LoginFailed(reason) => f.write_fmt(format_args!("login failed: {}", reason)),
+            ConnectionError::Timeout => f.write_str("connection timed out"),
+            ConnectionError::Closed => f.write_str("connection closed by server"),
+            ConnectionError::Reconnect => f.write_str("server requested reconnect"),
+        }
+    }
+}
+
+impl Error for ConnectionError {}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(err: std::io::Error) -> Self {
+        ConnectionError::Io(err)
+    }
+}
+
+impl From<tungstenite::Error> for ConnectionError {
+    fn from(err: tungstenite::Error) -> Self {
+        ConnectionError::Socket(err)
+    }
+}
+
+/// Exponential backoff for reconnection attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns delay before the next attempt, and doubles the delay for the attempt after it.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+        delay
+    }
+
+    /// Resets delay to its initial value. Should be called once connection is established.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Checks whether the message is a NOTICE telling us that our credentials were rejected.
+fn is_login_failure(message: &irc::Message) -> bool {
+    message.command.name == "NOTICE"
+        && message.tag_value("msg-id").is_none()
+        && match message.trailing {
+            Some(text) => {
+                text.starts_with("Login authentication failed") || text.starts_with("Improperly formatted auth")
+            }
+            None => false,
+        }
+}
+
+/// Waits until the server either welcomes us or rejects our credentials.
+async fn wait_for_welcome(rx_socket: &mut LineStream) -> Result<(), ConnectionError> {
+    while let Some(raw_message) = rx_socket.next().await {
+        let raw_message = raw_message?;
+        match irc::Message::parse(&raw_message) {
+            Ok(message) if message.command.name == "001" => return Ok(()),
+            Ok(message) if is_login_failure(&message) => {
+                return Err(ConnectionError::LoginFailed(message.trailing.unwrap_or("").to_string()));
+            }
+            Ok(message) => trace!("Received while logging in: {}", message),
+            Err(err) => error!("Error parsing message: {} (message = {})", err, raw_message),
+        }
+    }
+    Err(ConnectionError::Closed)
+}
+
+/// This function connects to the server, logs in and joins channels.
+pub(crate) async fn initialize(
+    transport: &dyn Transport,
+    username: &str,
+    password: &str,
+    channels: impl Iterator<Item = &String>,
+    joins: &TokenBucket,
+) -> Result<(LineSink, LineStream), ConnectionError> {
+    let (mut tx_socket, mut rx_socket) = transport.connect().await?;
+
+    info!(
+        "Authenticating with user name '{}', oauth token '{}'",
+        username,
+        "*".repeat(password.len())
+    );
+
+    // login to twitch IRC
+    tx_socket.send(format!("PASS oauth:{}", password)).await?;
+    tx_socket.send(format!("NICK {}", username)).await?;
+    tx_socket
+        .send("CAP REQ :twitch.tv/tags twitch.tv/commands twitch.tv/membership".to_owned())
+        .await?;
+
+    match tokio::timer::Timeout::new(wait_for_welcome(&mut rx_socket), LOGIN_TIMEOUT).await {
+        Ok(result) => result?,
+        Err(_) => return Err(ConnectionError::Timeout),
+    }
+
+    info!("Logged in successfully");
+
+    // join channels
+    for channel in channels {
+        joins.acquire(JOIN_LIMIT).await;
+        info!("Joining channel: {}", channel);
+        tx_socket.send(format!("JOIN #{}", channel)).await?;
+    }
+
+    Ok((tx_socket, rx_socket))
+}
+
+/// Reads messages from socket until connection is lost.
+async fn receiver_loop<T: 'static + Send + Sync>(
+    rx_socket: LineStream,
+    queues: Queues,
+    state: Arc<BotState<T>>,
+    messaging_state: Arc<MessagingState>,
+) -> ConnectionError {
+    let mut rx_socket = rx_socket;
+    let mut queues = queues;
+    let mut awaiting_pong = false;
+
+    loop {
+        let timeout = if awaiting_pong { PING_TIMEOUT } else { IDLE_TIMEOUT };
+        match tokio::timer::Timeout::new(rx_socket.next(), timeout).await {
+            Ok(Some(Ok(raw_message))) => {
+                awaiting_pong = false;
+                if let Err(err) = messaging::handle_message(&raw_message, &mut queues, &state, &messaging_state).await {
+                    return err;
+                }
+            }
+            Ok(Some(Err(err))) => return err,
+            Ok(None) => return ConnectionError::Closed,
+            Err(_) if awaiting_pong => return ConnectionError::Timeout,
+            Err(_) => {
+                info!("No messages received for {:?}, checking connection...", timeout);
+                if queues.tx_line.send("PING :tmi.twitch.tv".to_string()).await.is_err() {
+                    error!("Failed to submit PING: outgoing queue is closed");
+                }
+                awaiting_pong = true;
+            }
+        }
+    }
+}
+
+/// Checks whether the line should not be sent, because it joins a channel which `initialize` has
+/// already joined. Such lines could have been queued while we were disconnected.
+///
+/// Each channel in `rejoined` skips at most one JOIN, and stops skipping them once it is left.
+fn is_redundant_join(line: &str, rejoined: &mut HashSet<String>) -> bool {
+    if line.starts_with("JOIN #") {
+        rejoined.remove(&line["JOIN #".len()..])
+    } else {
+        if line.starts_with("PART #") {
+            rejoined.remove(&line["PART #".len()..]);
+        }
+        false
+    }
+}
+
+/// Writes outgoing lines to socket until connection is lost or the queue is closed.
+///
+/// A line that failed to be written is stored in `pending` to be retried once connection is restored.
+/// JOINs to channels in `rejoined` are not sent, see `is_redundant_join`.
+async fn writer_loop(
+    tx_socket: &mut LineSink,
+    rx_line: &mut Receiver<String>,
+    pending: &mut Option<String>,
+    rejoined: &mut HashSet<String>,
+) -> Result<(), ConnectionError> {
+    loop {
+        let line = match pending.take() {
+            Some(line) => line,
+            None => match rx_line.next().await {
+                Some(line) => line,
+                None => return Ok(()),
+            },
+        };
+        if is_redundant_join(&line, rejoined) {
+            info!("Already joined, not sending: {}", line);
+            continue;
+        }
+
+        // line stays pending until it is sent, as writer can be dropped (e.g. on disconnect) while sending it
+        *pending = Some(line.clone());
+        tx_socket.send(line).await?;
+        *pending = None;
+    }
+}
+
+/// Sends what is left in the outgoing queue, leaves all channels and closes connection.
+///
+/// Queue is drained until all its senders are gone, but no longer than `timeout`.
+async fn disconnect<T: 'static + Send + Sync>(
+    tx_socket: LineSink,
+    rx_line: &mut Receiver<String>,
+    pending: &mut Option<String>,
+    state: &BotState<T>,
+    timeout: Duration,
+) -> Result<(), ConnectionError> {
+    let mut tx_socket = tx_socket;
+
+    info!("Sending remaining messages...");
+    let mut rejoined = HashSet::new();
+    let writer = writer_loop(&mut tx_socket, rx_line, pending, &mut rejoined);
+    match tokio::timer::Timeout::new(writer, timeout).await {
+        Ok(result) => result?,
+        Err(_) => warn!("Failed to send remaining messages in {:?}, dropping them", timeout),
+    }
+
+    for channel in state.channels.read().await.iter() {
+        info!("Leaving channel: {}", channel);
+        tx_socket.send(format!("PART #{}", channel)).await?;
+    }
+
+    info!("Closing connection");
+    tx_socket.close().await
+}
+
+/// Maintains connection to the server, reconnecting with exponential backoff whenever it drops.
+///
+/// Outgoing lines are taken from `rx_line`, which survives reconnects. This function returns
+/// when shutdown is requested or when the server rejects our credentials. The outgoing queue
+/// cannot close before that, because `queues` keeps one of its senders alive. On shutdown,
+/// remaining outgoing lines are sent before disconnecting.
+pub(crate) async fn supervisor<T: 'static + Send + Sync>(
+    transport: &dyn Transport,
+    password: String,
+    rx_line: Receiver<String>,
+    queues: Queues,
+    state: Arc<BotState<T>>,
+    messaging_state: Arc<MessagingState>,
+    shutdown: ShutdownSignal,
+) -> Result<(), ConnectionError> {
+    let mut rx_line = rx_line;
+    let mut pending = None;
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60));
+
+    loop {
+        let channels: Vec<String> = state.channels.read().await.iter().cloned().collect();
+        let connection = initialize(
+            transport,
+            &state.username,
+            &password,
+            channels.iter(),
+            &messaging_state.joins,
+        );
+        let stopped = shutdown.wait();
+        pin_mut!(connection, stopped);
+
+        let connection = match select(connection, stopped).await {
+            Either::Left((connection, _)) => connection,
+            Either::Right(_) => {
+                info!("Shutdown requested, not connecting");
+                return Ok(());
+            }
+        };
+
+        match connection {
+            Ok((mut tx_socket, rx_socket)) => {
+                backoff.reset();
+
+                // JOINs queued while we were disconnected would join these channels once again
+                let mut rejoined: HashSet<String> = channels.iter().cloned().collect();
+
+                let outcome = {
+                    let receiver = receiver_loop(rx_socket, queues.clone(), state.clone(), messaging_state.clone());
+                    let writer = writer_loop(&mut tx_socket, &mut rx_line, &mut pending, &mut rejoined);
+                    let stopped = shutdown.wait();
+                    pin_mut!(receiver, writer, stopped);
+
+                    match select(select(receiver, writer), stopped).await {
+                        Either::Left((Either::Left((err, _)), _)) => Outcome::Lost(err),
+                        Either::Left((Either::Right((Err(err), _)), _)) => Outcome::Lost(err),
+                        Either::Left((Either::Right((Ok(()), _)), _)) => Outcome::QueueClosed,
+                        Either::Right(_) => Outcome::Shutdown,
+                    }
+                };
+
+                match outcome {
+                    Outcome::Lost(ConnectionError::Reconnect) => {
+                        info!("Reconnecting on server request...");
+                        continue;
+                    }
+                    Outcome::Lost(err) => {
+                        warn!("Connection lost: {}", err);
+                    }
+                    Outcome::QueueClosed => {
+                        info!("Outgoing queue is closed, disconnecting");
+                        return tx_socket.close().await;
+                    }
+                    Outcome::Shutdown => {
+                        info!("Shutdown requested, disconnecting");
+                        // stop accepting new commands: once our senders are gone, executor and
+                        // sender loops finish what they have and close the outgoing queue
+                        drop(queues);
+                        return disconnect(tx_socket, &mut rx_line, &mut pending, &state, shutdown.timeout()).await;
+                    }
+                }
+            }
+            Err(ConnectionError::LoginFailed(reason)) => {
+                error!("Server rejected our credentials: {}", reason);
+                return Err(ConnectionError::LoginFailed(reason));
+            }
+            Err(err) => error!("Failed to connect: {}", err),
+        }
+
+        let delay = backoff.next_delay();
+        info!("Reconnecting in {:?}...", delay);
+        let delay = tokio::timer::delay_for(delay);
+        let stopped = shutdown.wait();
+        pin_mut!(delay, stopped);
+        if let Either::Right(_) = select(delay, stopped).await {
+            info!("Shutdown requested, not reconnecting");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        for _ in 0..10 {
+            assert!(backoff.next_delay() <= Duration::from_secs(5), "delay exceeds maximum");
+        }
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_backoff_can_be_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_line_being_sent_stays_pending() {
+        let runtime = tokio::runtime::Builder::new()
+            .build()
+            .expect("Failed to create runtime");
+
+        // nobody reads from the other end, so sending never finishes
+        let (tx, _rx) = futures::channel::mpsc::channel(0);
+        let mut tx_socket: LineSink = Box::pin(tx.sink_map_err(|_| ConnectionError::Closed));
+        let (mut tx_line, mut rx_line) = futures::channel::mpsc::channel(1);
+        tx_line.try_send("PRIVMSG #test :hello".to_string()).unwrap();
+
+        let mut pending = None;
+        let mut rejoined = HashSet::new();
+        let writer = writer_loop(&mut tx_socket, &mut rx_line, &mut pending, &mut rejoined);
+        let result = runtime.block_on(tokio::timer::Timeout::new(writer, Duration::from_millis(50)));
+
+        assert!(result.is_err(), "writer should be stuck");
+        assert_eq!(pending.as_deref(), Some("PRIVMSG #test :hello"));
+    }
+
+    #[test]
+    fn test_rejoined_channels_are_not_joined_again() {
+        let mut rejoined: HashSet<String> = vec!["test".to_string()].into_iter().collect();
+
+        assert!(!is_redundant_join("JOIN #other", &mut rejoined));
+        assert!(is_redundant_join("JOIN #test", &mut rejoined));
+        assert!(
+            !is_redundant_join("JOIN #test", &mut rejoined),
+            "only one JOIN should be skipped"
+        );
+    }
+
+    #[test]
+    fn test_left_channels_can_be_joined_again() {
+        let mut rejoined: HashSet<String> = vec!["test".to_string()].into_iter().collect();
+
+        assert!(!is_redundant_join("PRIVMSG #test :JOIN #test", &mut rejoined));
+        assert!(!is_redundant_join("PART #test", &mut rejoined));
+        assert!(!is_redundant_join("JOIN #test", &mut rejoined));
+    }
+}
@@ -123,6 +123,11 @@ where
     pub fn add_channel(&self, channel: K, cooldown: Duration, reset: bool) {
         self.cooldown_map.insert(channel, CooldownData::new(cooldown, reset));
     }
+
+    /// Removes channel from tracker.
+    pub fn remove_channel(&self, channel: &K) {
+        self.cooldown_map.remove(channel);
+    }
 }
 
 #[cfg(test)]
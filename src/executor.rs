@@ -8,12 +8,14 @@ use std::time::Duration;
 use futures::channel::mpsc::{Receiver, Sender};
 use futures::lock::Mutex;
 use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
 
 use crate::cooldown::{CooldownState, CooldownTracker};
 use crate::irc;
 use crate::messaging::PreparedMessage;
 use crate::permissions::PermissionLevel;
 use crate::state::BotState;
+use crate::twitch;
 
 type GlobalCooldownTracker = CooldownTracker<String>;
 
@@ -25,22 +27,45 @@ pub struct CommandCooldown {
     pub user: Option<Duration>,
 }
 
+/// What to do with a reply which is too long to be sent as a single chat message.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Cut the reply short, ending it with an ellipsis.
+    Truncate,
+    /// Send the reply as several messages, split between words.
+    Split,
+}
+
 #[async_trait]
 pub trait ExecutableCommand<T: 'static + Send + Sync> {
-    async fn execute<'a>(&self, command: &'a str, message: irc::Message<'a>, state: &BotState<T>) -> ExecutionOutcome;
+    /// Executes the command. `event` is what `message` was parsed into, either a chat message or a whisper.
+    async fn execute<'a>(
+        &self,
+        command: &'a str,
+        message: irc::Message<'a>,
+        event: &'a twitch::Event,
+        state: &BotState<T>,
+    ) -> ExecutionOutcome;
 
     fn help(&self) -> String;
 
     fn cooldown(&self) -> CommandCooldown;
 
     fn level(&self) -> PermissionLevel;
+
+    fn overflow(&self) -> Overflow {
+        Overflow::Truncate
+    }
 }
 
 pub type ShareableExecutableCommand<T> = Box<dyn ExecutableCommand<T> + 'static + Send + Sync>;
 
 #[derive(Debug, Clone)]
 pub struct PreparedCommand {
-    pub message: String,
+    pub message: irc::OwnedMessage,
+    /// Chat message or whisper `message` was parsed into.
+    pub event: twitch::Event,
     pub command: String,
 }
 
@@ -48,15 +73,97 @@ pub struct PreparedCommand {
 pub enum ExecutionOutcome {
     Success(PreparedMessage),
     SilentSuccess,
+    /// Leaves a channel once the reply, if any, is sent. See `then_part`.
+    Part(Option<PreparedMessage>, String),
     Error(String),
 }
 
 impl ExecutionOutcome {
     pub fn success(channel: String, message: String) -> ExecutionOutcome {
-        ExecutionOutcome::Success(PreparedMessage { channel, message })
+        ExecutionOutcome::Success(PreparedMessage {
+            channel,
+            message,
+            overflow: Overflow::Truncate,
+            priority: false,
+            reply_to: None,
+            whisper: false,
+            leave: false,
+            is_retry: false,
+        })
+    }
+
+    /// Like `success`, but the message is sent ahead of other messages waiting to be sent to the channel.
+    /// Meant for moderation actions, such as warning a user. Like any other message, it is never run as
+    /// a chat command.
+    pub fn priority(channel: String, message: String) -> ExecutionOutcome {
+        ExecutionOutcome::Success(PreparedMessage {
+            channel,
+            message,
+            overflow: Overflow::Truncate,
+            priority: true,
+            reply_to: None,
+            whisper: false,
+            leave: false,
+            is_retry: false,
+        })
+    }
+
+    /// Whispers the message to a user instead of sending it to a channel.
+    pub fn whisper(login: String, message: String) -> ExecutionOutcome {
+        ExecutionOutcome::Success(PreparedMessage {
+            channel: login,
+            message,
+            overflow: Overflow::Truncate,
+            priority: false,
+            reply_to: None,
+            whisper: true,
+            leave: false,
+            is_retry: false,
+        })
+    }
+
+    /// Sends the text where the given message came from: to its channel, or as a whisper back to
+    /// its sender if it was whispered to us.
+    pub fn respond(message: &irc::Message<'_>, text: String) -> ExecutionOutcome {
+        match (message.channel(), message.nick()) {
+            (Some(channel), _) => ExecutionOutcome::success(channel.to_string(), text),
+            (None, Some(login)) => ExecutionOutcome::whisper(login.to_string(), text),
+            (None, None) => ExecutionOutcome::Error(format!("nowhere to respond to: {}", message)),
+        }
+    }
+
+    /// Like `respond`, but in channels the message is sent as a reply to the given one, so that chat
+    /// clients show them threaded. Falls back to a plain message if the original one has no id.
+    pub fn reply(message: &irc::Message<'_>, text: String) -> ExecutionOutcome {
+        match ExecutionOutcome::respond(message, text) {
+            ExecutionOutcome::Success(reply) if !reply.whisper => ExecutionOutcome::Success(PreparedMessage {
+                reply_to: message.tag_value("id").map(|id| id.into_owned()),
+                ..reply
+            }),
+            outcome => outcome,
+        }
+    }
+
+    /// Leaves a channel once this outcome's reply is sent, rather than risking that the reply is dropped because
+    /// the channel has already been left. Channel name should be valid (see `valid_channel_name`).
+    pub fn then_part(self, channel: String) -> ExecutionOutcome {
+        match self {
+            ExecutionOutcome::Success(reply) => ExecutionOutcome::Part(Some(reply), channel),
+            ExecutionOutcome::SilentSuccess => ExecutionOutcome::Part(None, channel),
+            outcome => outcome,
+        }
     }
 }
 
+async fn submit_reply(tx_message: &Mutex<Sender<PreparedMessage>>, message: PreparedMessage) {
+    tx_message
+        .lock()
+        .await
+        .send(message)
+        .await
+        .expect("Failed to submit message to message queue");
+}
+
 async fn execute<T: 'static + std::marker::Send + std::marker::Sync>(
     command: PreparedCommand,
     state: &BotState<T>,
@@ -64,7 +171,7 @@ async fn execute<T: 'static + std::marker::Send + std::marker::Sync>(
     global_cooldowns: &GlobalCooldownTracker,
     user_cooldowns: &UserCooldownTracker,
 ) {
-    let message = irc::Message::parse(&command.message).unwrap();
+    let message = command.message.as_message();
 
     let (command_name, command_body) = {
         let mut command_split = command.command.splitn(2, ' ');
@@ -74,12 +181,12 @@ async fn execute<T: 'static + std::marker::Send + std::marker::Sync>(
         )
     };
 
-    let user = message.tag_value("display-name").unwrap_or("");
+    let user = command.event.user().map(|user| user.login.as_str()).unwrap_or_default();
 
     let outcome = match state.commands.get(&command_name) {
         Some(executable) => {
             // 1. consult user permissions
-            if !state.permissions.get(user).permits(executable.level()) {
+            if !state.permissions.get(&user).permits(executable.level()) {
                 info!("user {} lacks permissions to execute '{}'", user, command_name);
                 return;
             }
@@ -177,7 +284,14 @@ async fn execute<T: 'static + std::marker::Send + std::marker::Sync>(
             }
 
             info!("executing command: {}", command_name);
-            executable.execute(command_body, message, &state).await
+            let overflow = executable.overflow();
+            match executable.execute(command_body, message, &command.event, &state).await {
+                ExecutionOutcome::Success(reply) => ExecutionOutcome::Success(PreparedMessage { overflow, ..reply }),
+                ExecutionOutcome::Part(Some(reply), channel) => {
+                    ExecutionOutcome::Part(Some(PreparedMessage { overflow, ..reply }), channel)
+                }
+                outcome => outcome,
+            }
         }
         None => {
             info!("no such command: {}", command_name);
@@ -186,13 +300,15 @@ async fn execute<T: 'static + std::marker::Send + std::marker::Sync>(
     };
 
     match outcome {
-        ExecutionOutcome::Success(message) => {
-            tx_message
-                .lock()
-                .await
-                .send(message)
-                .await
-                .expect("Failed to submit message to message queue");
+        ExecutionOutcome::Success(message) => submit_reply(tx_message, message).await,
+        ExecutionOutcome::Part(reply, channel) => {
+            if let Some(message) = reply {
+                submit_reply(tx_message, message).await;
+            }
+            // reply is queued by now, and channel is left only after messages queued for it are sent
+            if let Err(err) = state.part_channel(&channel).await {
+                error!("Failed to request part of {}: {}", channel, err);
+            }
         }
         ExecutionOutcome::SilentSuccess => {
             info!("Successfully executed command: {:?}", command.command);
@@ -1,4 +1,5 @@
 use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use async_std::sync::RwLock;
@@ -9,12 +10,14 @@ pub struct HistoryEntry<Data> {
     times_found: usize,
 }
 
+type Queue<Data> = Arc<RwLock<VecDeque<HistoryEntry<Data>>>>;
+
 // TODO improve this struct
 // this is a prototype that is far from optimal
 // ideally we don't need to store actual messages -- can just check
 // hashes or something like this
 pub struct History<Data> {
-    channels: HashMap<String, RwLock<VecDeque<HistoryEntry<Data>>>>,
+    channels: std::sync::RwLock<HashMap<String, Queue<Data>>>,
     ttl: Duration,
 }
 
@@ -24,17 +27,44 @@ where
 {
     pub fn new(channels: Vec<String>, ttl: Duration) -> History<Data> {
         History {
-            channels: channels
-                .into_iter()
-                .map(|c| (c, RwLock::new(VecDeque::new())))
-                .collect(),
+            channels: std::sync::RwLock::new(
+                channels
+                    .into_iter()
+                    .map(|c| (c, Arc::new(RwLock::new(VecDeque::new()))))
+                    .collect(),
+            ),
             ttl,
         }
     }
 
+    /// Adds a new channel to history. Existing channel history is kept intact.
+    pub fn add_channel(&self, channel: String) {
+        self.channels
+            .write()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .entry(channel)
+            .or_insert_with(|| Arc::new(RwLock::new(VecDeque::new())));
+    }
+
+    /// Removes channel from history along with all its items.
+    pub fn remove_channel(&self, channel: &str) {
+        self.channels
+            .write()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .remove(channel);
+    }
+
+    fn queue(&self, channel: &str) -> Option<Queue<Data>> {
+        self.channels
+            .read()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .get(channel)
+            .cloned()
+    }
+
     /// Adds item to a channel's queue.
     pub async fn push(&self, channel: &str, data: Data) {
-        if let Some(lock) = self.channels.get(channel) {
+        if let Some(lock) = self.queue(channel) {
             let mut queue = lock.write().await;
             queue.push_back(HistoryEntry {
                 timestamp: Instant::now(),
@@ -50,7 +80,7 @@ where
     /// The number of items this message was searched for and found is returned.
     pub async fn contains(&self, channel: &str, data: &Data) -> Option<usize> {
         let ttl = self.ttl;
-        if let Some(lock) = self.channels.get(channel) {
+        if let Some(lock) = self.queue(channel) {
             let now = Instant::now();
 
             let mut queue = lock.write().await;
@@ -76,6 +106,54 @@ where
     }
 }
 
+/// Recent chat messages, kept per channel. Only the last `capacity` messages of each channel are remembered.
+pub struct ChatLog {
+    channels: std::sync::RwLock<HashMap<String, VecDeque<(String, String)>>>,
+    capacity: usize,
+}
+
+impl ChatLog {
+    pub fn new(capacity: usize) -> ChatLog {
+        ChatLog {
+            channels: std::sync::RwLock::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Remembers a message sent by a user to a channel.
+    pub fn push(&self, channel: &str, login: &str, text: &str) {
+        let mut channels = self
+            .channels
+            .write()
+            .expect("lock is poisoned, but this shouldn't have happened");
+        let messages = channels.entry(channel.to_string()).or_default();
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back((login.to_string(), text.to_string()));
+    }
+
+    /// Finds the last remembered message sent by a user to a channel.
+    pub fn last_message(&self, channel: &str, login: &str) -> Option<String> {
+        self.channels
+            .read()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .get(channel)?
+            .iter()
+            .rev()
+            .find(|(author, _)| author == login)
+            .map(|(_, text)| text.clone())
+    }
+
+    /// Forgets everything said in a channel.
+    pub fn remove_channel(&self, channel: &str) {
+        self.channels
+            .write()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .remove(channel);
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -169,4 +247,58 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn test_channels_can_be_added_and_removed() {
+        async_test!({
+            let history = History::new(vec![], Duration::from_secs(1));
+
+            history.add_channel("test".to_string());
+            history.push("test", "message".to_string()).await;
+
+            match history.contains("test", &"message".to_string()).await {
+                Some(1) => assert!(true),
+                Some(_) => assert!(false, "message was inserted, but cannot be found"),
+                None => assert!(false, "channel was not added"),
+            }
+
+            history.remove_channel("test");
+
+            match history.contains("test", &"message".to_string()).await {
+                Some(_) => assert!(false, "channel should have been removed"),
+                None => assert!(true),
+            }
+        });
+    }
+
+    #[test]
+    fn test_last_message_of_user_is_found() {
+        let log = ChatLog::new(10);
+
+        log.push("test", "alice", "first");
+        log.push("test", "bob", "hello");
+        log.push("test", "alice", "second");
+        log.push("other", "alice", "elsewhere");
+
+        assert_eq!(log.last_message("test", "alice"), Some("second".to_string()));
+        assert_eq!(log.last_message("test", "bob"), Some("hello".to_string()));
+        assert_eq!(log.last_message("test", "carol"), None);
+        assert_eq!(log.last_message("nowhere", "alice"), None);
+
+        log.remove_channel("test");
+        assert_eq!(log.last_message("test", "alice"), None);
+        assert_eq!(log.last_message("other", "alice"), Some("elsewhere".to_string()));
+    }
+
+    #[test]
+    fn test_chat_log_is_bounded() {
+        let log = ChatLog::new(2);
+
+        log.push("test", "alice", "old");
+        log.push("test", "bob", "one");
+        log.push("test", "bob", "two");
+
+        assert_eq!(log.last_message("test", "alice"), None);
+        assert_eq!(log.last_message("test", "bob"), Some("two".to_string()));
+    }
 }
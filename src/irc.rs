@@ -1,9 +1,60 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::pin::Pin;
 
-type Tags<'a> = BTreeMap<&'a str, Option<&'a str>>;
+/// Tags of a message. Values are stored in their escaped (on-the-wire) form,
+/// use `Message::tag_value` to get unescaped values.
+type Tags<'a> = BTreeMap<&'a str, Option<Cow<'a, str>>>;
+
+/// Escapes tag value according to IRCv3 message tags specification.
+pub fn escape_tag_value(value: &str) -> Cow<str> {
+    if !value.contains(|c| c == ';' || c == ' ' || c == '\\' || c == '\r' || c == '\n') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len() + 8);
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Unescapes tag value according to IRCv3 message tags specification.
+///
+/// Unknown escape sequences are replaced by the escaped character, and a trailing
+/// lone backslash is dropped.
+pub fn unescape_tag_value(value: &str) -> Cow<str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => {}
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    Cow::Owned(unescaped)
+}
 
 /// Prefix part of an IRC message. Roughly corresponds to what is meant by "prefix"
 /// in RFC1459 (see `Message` description for more info)
@@ -92,7 +143,7 @@ impl Message<'_> {
                 if !pair.is_empty() {
                     let mut iter = pair.splitn(2, '=');
                     let key = iter.next().unwrap();
-                    let val = iter.next();
+                    let val = iter.next().map(Cow::Borrowed);
                     tags.insert(key, val);
                 }
             }
@@ -160,14 +211,37 @@ impl Message<'_> {
         Ok(message)
     }
 
-    pub fn tag_value(&self, key: &str) -> Option<&str> {
-        *self.tags.get(key)?
+    /// Returns unescaped value of a tag, if the tag is present and has a value.
+    pub fn tag_value(&self, key: &str) -> Option<Cow<str>> {
+        self.tags.get(key)?.as_ref().map(|value| unescape_tag_value(value))
+    }
+
+    /// Copies this message into an `OwnedMessage`.
+    pub fn to_owned_message(&self) -> OwnedMessage {
+        OwnedMessage::from(self)
     }
 
     pub fn first_arg_as_channel_name(&self) -> Option<&str> {
         self.command.args.first().map(|s| s.trim_start_matches('#'))
     }
 
+    /// Channel the message was sent to. Messages not sent to a channel (e.g. whispers) have none.
+    pub fn channel(&self) -> Option<&str> {
+        self.command
+            .args
+            .first()
+            .filter(|arg| arg.starts_with('#'))
+            .map(|arg| &arg[1..])
+    }
+
+    /// Nickname of the sender, if the message has one.
+    pub fn nick(&self) -> Option<&str> {
+        match self.prefix {
+            Prefix::Full { nick, .. } => Some(nick),
+            _ => None,
+        }
+    }
+
     /// Splits the trailing into two parts - before the first space character and after.
     pub fn arg_split(&self) -> (&str, Option<&str>) {
         match self.trailing {
@@ -230,6 +304,91 @@ impl Display for Message<'_> {
     }
 }
 
+/// Owned counterpart of `Prefix`.
+#[derive(Debug, Clone)]
+enum OwnedPrefix {
+    Full { nick: String, user: String, host: String },
+    UserHost { user: String, host: String },
+    Host(String),
+    None,
+}
+
+/// Owned counterpart of `Message`, which does not borrow from the raw string and
+/// therefore can be sent between tasks.
+///
+/// Use `OwnedMessage::as_message` to access contents of the message.
+#[derive(Debug, Clone)]
+pub struct OwnedMessage {
+    tags: BTreeMap<String, Option<String>>,
+    prefix: OwnedPrefix,
+    command: String,
+    args: Vec<String>,
+    trailing: Option<String>,
+}
+
+impl OwnedMessage {
+    /// Parses a string into an owned Twitch IRC message.
+    pub fn parse(raw: &str) -> Result<OwnedMessage, Box<dyn Error>> {
+        Ok(Message::parse(raw)?.to_owned_message())
+    }
+
+    /// Borrows this message as `Message`. No parsing happens here.
+    pub fn as_message(&self) -> Message<'_> {
+        Message {
+            tags: self
+                .tags
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_ref().map(|v| Cow::Borrowed(v.as_str()))))
+                .collect(),
+            prefix: match &self.prefix {
+                OwnedPrefix::Full { nick, user, host } => Prefix::Full { nick, user, host },
+                OwnedPrefix::UserHost { user, host } => Prefix::UserHost { user, host },
+                OwnedPrefix::Host(host) => Prefix::Host(host),
+                OwnedPrefix::None => Prefix::None,
+            },
+            command: Command {
+                name: &self.command,
+                args: self.args.iter().map(|s| s.as_str()).collect(),
+            },
+            trailing: self.trailing.as_ref().map(|s| s.as_str()),
+        }
+    }
+}
+
+impl From<&Message<'_>> for OwnedMessage {
+    fn from(message: &Message<'_>) -> Self {
+        OwnedMessage {
+            tags: message
+                .tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_ref().map(|v| v.to_string())))
+                .collect(),
+            prefix: match message.prefix {
+                Prefix::Full { nick, user, host } => OwnedPrefix::Full {
+                    nick: nick.to_string(),
+                    user: user.to_string(),
+                    host: host.to_string(),
+                },
+                Prefix::UserHost { user, host } => OwnedPrefix::UserHost {
+                    user: user.to_string(),
+                    host: host.to_string(),
+                },
+                Prefix::Host(host) => OwnedPrefix::Host(host.to_string()),
+                Prefix::None => OwnedPrefix::None,
+            },
+            command: message.command.name.to_string(),
+            args: message.command.args.iter().map(|s| s.to_string()).collect(),
+            trailing: message.trailing.map(|s| s.to_string()),
+        }
+    }
+}
+
+impl Display for OwnedMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.as_message().fmt(f)
+    }
+}
+
 pub struct MessageBuilder<'a> {
     message: Message<'a>,
 }
@@ -250,8 +409,9 @@ impl<'a> MessageBuilder<'a> {
         self
     }
 
+    /// Adds a tag to the message. Value is escaped automatically.
     pub fn with_tag(&'a mut self, key: &'a str, value: Option<&'a str>) -> &'a mut MessageBuilder {
-        self.message.tags.insert(key, value);
+        self.message.tags.insert(key, value.map(escape_tag_value));
         self
     }
 
@@ -313,25 +473,16 @@ mod tests {
     fn test_msg_parse_single_tag() {
         let parsed = Message::parse("@aaa=a_value :host.com CAP LS").expect("Failed to parse message");
         assert!(!parsed.tags.is_empty());
-        assert_eq!(
-            parsed.tags.get("aaa").expect("Expected key is not present").unwrap(),
-            "a_value"
-        );
+        assert_eq!(parsed.tag_value("aaa").expect("Expected key is not present"), "a_value");
     }
 
     #[test]
     fn test_msg_parse_multiple_tags() {
         let parsed = Message::parse("@a=a_value;b;c=c_value :host.com CAP LS").expect("Failed to parse message");
         assert!(!parsed.tags.is_empty());
-        assert_eq!(
-            parsed.tags.get("a").expect("Expected key is not present").unwrap(),
-            "a_value"
-        );
+        assert_eq!(parsed.tag_value("a").expect("Expected key is not present"), "a_value");
         assert!(parsed.tags.get("b").expect("Expected key is not present").is_none());
-        assert_eq!(
-            parsed.tags.get("c").expect("Expected key is not present").unwrap(),
-            "c_value"
-        );
+        assert_eq!(parsed.tag_value("c").expect("Expected key is not present"), "c_value");
     }
 
     #[test]
@@ -340,6 +491,21 @@ mod tests {
         assert_eq!(parsed.trailing.expect("Trailing should not be None"), "trailing");
     }
 
+    #[test]
+    fn test_msg_channel_and_nick() {
+        let privmsg = Message::parse(":alice!alice@alice.tmi.twitch.tv PRIVMSG #channel :hi").unwrap();
+        assert_eq!(privmsg.channel(), Some("channel"));
+        assert_eq!(privmsg.nick(), Some("alice"));
+
+        let whisper = Message::parse(":alice!alice@alice.tmi.twitch.tv WHISPER bot :hi").unwrap();
+        assert_eq!(whisper.channel(), None);
+        assert_eq!(whisper.nick(), Some("alice"));
+
+        let ping = Message::parse("PING :tmi.twitch.tv").unwrap();
+        assert_eq!(ping.channel(), None);
+        assert_eq!(ping.nick(), None);
+    }
+
     #[test]
     fn test_msg_build_simple() {
         let message = MessageBuilder::new("CAP")
@@ -363,13 +529,116 @@ mod tests {
             .with_tag("ck", None)
             .string();
 
-        let tags = Message::parse(&message).expect("message is unparseable").tags;
+        let parsed = Message::parse(&message).expect("message is unparseable");
+        let tags = &parsed.tags;
 
-        assert_eq!(tags.get("ak").expect("no key ak").expect("should have value"), "av");
-        assert_eq!(tags.get("bk").expect("no key bk").expect("should have value"), "bv");
+        assert_eq!(parsed.tag_value("ak").expect("no value for key ak"), "av");
+        assert_eq!(parsed.tag_value("bk").expect("no value for key bk"), "bv");
         assert!(tags.get("ck").expect("no key ck").is_none());
     }
 
+    #[test]
+    fn test_msg_parse_unescapes_tag_values() {
+        let parsed = Message::parse(
+            "@system-msg=5\\sraiders\\sfrom\\sTestChannel;semi=a\\:b;slash=c\\\\d;crlf=\\r\\n;\
+             unknown=\\x;trailing=e\\ :tmi.twitch.tv USERNOTICE #channel",
+        )
+        .expect("Failed to parse message");
+
+        assert_eq!(parsed.tag_value("system-msg").unwrap(), "5 raiders from TestChannel");
+        assert_eq!(parsed.tag_value("semi").unwrap(), "a;b");
+        assert_eq!(parsed.tag_value("slash").unwrap(), "c\\d");
+        assert_eq!(parsed.tag_value("crlf").unwrap(), "\r\n");
+        assert_eq!(parsed.tag_value("unknown").unwrap(), "x");
+        assert_eq!(parsed.tag_value("trailing").unwrap(), "e");
+    }
+
+    #[test]
+    fn test_msg_parse_does_not_copy_unescaped_tag_values() {
+        let parsed = Message::parse("@a=plain :tmi.twitch.tv CAP LS").expect("Failed to parse message");
+
+        match parsed.tag_value("a").unwrap() {
+            Cow::Borrowed(value) => assert_eq!(value, "plain"),
+            Cow::Owned(_) => assert!(false, "value without escapes should be borrowed"),
+        }
+    }
+
+    #[test]
+    fn test_msg_build_escapes_tag_values() {
+        let message = MessageBuilder::new("PRIVMSG")
+            .with_arg("#channel")
+            .with_trailing("message")
+            .with_tag("k", Some("a b;c\\d\r\n"))
+            .string();
+
+        assert_eq!(message, "@k=a\\sb\\:c\\\\d\\r\\n PRIVMSG #channel :message");
+    }
+
+    #[test]
+    fn test_msg_tag_values_round_trip() {
+        let values = [
+            "",
+            "plain",
+            "with space",
+            "with;semicolon",
+            "back\\slash",
+            "\\s is not a space",
+            "line\r\nbreak",
+            "all of ; them \\ \r \n at once",
+        ];
+
+        for value in values.iter() {
+            let message = MessageBuilder::new("CAP").with_tag("key", Some(value)).string();
+            let parsed = Message::parse(&message).expect("message is unparseable");
+
+            assert_eq!(
+                parsed.tag_value("key").expect("no key"),
+                *value,
+                "value was not preserved"
+            );
+            assert_eq!(format!("{}", parsed), message, "message was not preserved");
+        }
+    }
+
+    #[test]
+    fn test_owned_msg_preserves_message() {
+        let raw = "@a=a_value;b;c=with\\sspace :nick!user@host.com PRIVMSG #channel arg :trailing part";
+
+        let owned = OwnedMessage::parse(raw).expect("Failed to parse message");
+        let message = owned.as_message();
+
+        assert_eq!(message.command.name, "PRIVMSG");
+        assert_eq!(message.command.args, vec!["#channel", "arg"]);
+        assert_eq!(message.trailing, Some("trailing part"));
+        assert_eq!(message.tag_value("c").expect("no value for key c"), "with space");
+        match message.prefix {
+            Prefix::Full { nick, user, host } => {
+                assert_eq!(nick, "nick");
+                assert_eq!(user, "user");
+                assert_eq!(host, "host.com");
+            }
+            _ => assert!(false),
+        };
+        assert_eq!(format!("{}", owned), raw);
+    }
+
+    #[test]
+    fn test_owned_msg_can_be_sent_between_threads() {
+        let owned = OwnedMessage::parse(":host.com CAP LS :trailing").expect("Failed to parse message");
+
+        let trailing = std::thread::spawn(move || owned.as_message().trailing.map(|s| s.to_string()))
+            .join()
+            .expect("thread panicked");
+
+        assert_eq!(trailing.expect("Trailing should not be None"), "trailing");
+    }
+
+    #[test]
+    fn test_owned_msg_parse_fails_on_malformed_input() {
+        assert!(OwnedMessage::parse("").is_err());
+        assert!(OwnedMessage::parse("@tags-without-command").is_err());
+    }
+
     #[bench]
     fn bench_msg_parse_simple(b: &mut Bencher) {
         b.iter(|| Message::parse("CAP LS").expect("Failed to parse message"));
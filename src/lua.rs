@@ -1,7 +1,43 @@
-use rlua::{Context, Error, HookTriggers, Value};
+use futures::channel::oneshot;
+use futures::future::{join_all, FutureExt, Shared};
+use log::*;
+use rlua::{Context, Error, HookTriggers, StdLib, Table, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
-use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
-use std::sync::Arc;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::storage::Storage;
+use crate::twitch;
+
+/// How many keys a single store can have.
+const MAX_STORE_KEYS: usize = 100;
+
+/// Longest key a store accepts, in bytes.
+const MAX_STORE_KEY_LENGTH: usize = 64;
+
+/// How much data a single store can have, in bytes of keys and values.
+const MAX_STORE_SIZE: usize = 16 * (1 << 10);
+
+/// Longest string pattern functions (`find`, `match`, `gmatch` and `gsub`) search in, in bytes. Library functions
+/// can't be interrupted, so this keeps the time code can spend in them short.
+const MAX_PATTERN_SUBJECT_LENGTH: usize = 1 << 10;
+
+/// Longest pattern these functions accept, in bytes.
+const MAX_PATTERN_LENGTH: usize = 64;
+
+/// How many ways to match a pattern against a string pattern functions allow, counting every choice of where its
+/// quantifiers stop. Each quantifier can backtrack over the whole string, so this is what bounds their time.
+const MAX_PATTERN_STEPS: usize = 1 << 24;
+
+/// Most times `string.rep` repeats a string. Repeating an empty one takes no memory, but takes time all the same.
+const MAX_REPETITIONS: usize = 1 << 16;
 
 #[derive(Clone)]
 pub enum ExecutionStatus {
@@ -40,37 +76,532 @@ impl rlua::FromLua<'_> for ExecutionStatus {
     }
 }
 
-/// Sandbox supplied user code on lua side
-fn sandbox(untrusted_code: &str) -> String {
+/// Parts of the standard library untrusted code has access to, by library. Base functions are listed
+/// under the empty name.
+///
+/// `load`, `setmetatable` and `rawset` are not listed as they are only given out wrapped (see `sandbox`), and
+/// neither is `math.random`, which is replaced with a seeded one (see `add_context`).
+const SAFE_LIBRARY: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "assert",
+            "error",
+            "getmetatable",
+            "ipairs",
+            "next",
+            "pairs",
+            "pcall",
+            "rawequal",
+            "rawget",
+            "rawlen",
+            "select",
+            "tonumber",
+            "tostring",
+            "type",
+            "xpcall",
+        ],
+    ),
+    (
+        "string",
+        &[
+            "byte", "char", "find", "format", "gmatch", "gsub", "len", "lower", "match", "rep", "reverse", "sub",
+            "upper",
+        ],
+    ),
+    (
+        "math",
+        &[
+            "abs",
+            "acos",
+            "asin",
+            "atan",
+            "ceil",
+            "cos",
+            "deg",
+            "exp",
+            "floor",
+            "fmod",
+            "huge",
+            "log",
+            "max",
+            "maxinteger",
+            "min",
+            "mininteger",
+            "modf",
+            "pi",
+            "rad",
+            "sin",
+            "sqrt",
+            "tan",
+            "tointeger",
+            "type",
+            "ult",
+        ],
+    ),
+    (
+        "table",
+        &["concat", "insert", "move", "pack", "remove", "sort", "unpack"],
+    ),
+    ("utf8", &["char", "charpattern", "codepoint", "codes", "len", "offset"]),
+];
+
+/// What code knows about the message it was sent with.
+///
+/// It is exposed to code as `ctx` table, along with helper functions `random`, `emotes` and `last_message`.
+#[derive(Debug, Clone, Default)]
+pub struct LuaContext {
+    pub login: String,
+    pub display_name: String,
+    pub badges: Vec<twitch::Badge>,
+    /// Channel the message was sent to, `None` for whispers.
+    pub channel: Option<String>,
+    pub args: Vec<String>,
+    pub message_id: Option<String>,
+    /// Seconds since unix epoch.
+    pub time: u64,
+    /// Seed for `random`, so that every run gets different numbers.
+    pub seed: u64,
+    /// Names of emotes used in the message.
+    pub emotes: Vec<String>,
+    /// Last message the user sent to the channel before this one.
+    pub last_message: Option<String>,
+    /// Where code keeps its data between runs. Without it, `store` is not available.
+    pub store: Option<LuaStore>,
+}
+
+impl LuaContext {
+    /// Describes a chat message or a whisper. Last message of the user is not known to the message itself,
+    /// so it is left for the caller to fill in.
+    pub fn of(event: &twitch::Event, args: &str) -> LuaContext {
+        let (user, channel, message_id, text, emotes) = match event.clone() {
+            twitch::Event::Privmsg(privmsg) => (
+                privmsg.user,
+                Some(privmsg.channel),
+                privmsg.id,
+                privmsg.text,
+                privmsg.emotes,
+            ),
+            twitch::Event::Whisper(whisper) => (whisper.user, None, whisper.id, whisper.text, whisper.emotes),
+            _ => (twitch::User::default(), None, None, String::new(), vec![]),
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut hasher = RandomState::new().build_hasher();
+        now.hash(&mut hasher);
+        message_id.hash(&mut hasher);
+
+        LuaContext {
+            login: user.login,
+            display_name: user.display_name,
+            badges: user.badges,
+            channel,
+            args: args.split_whitespace().map(|arg| arg.to_string()).collect(),
+            message_id,
+            time: now.as_secs(),
+            seed: hasher.finish(),
+            emotes: emote_names(&text, &emotes),
+            last_message: None,
+            store: None,
+        }
+    }
+}
+
+/// Data code keeps between runs in a `Storage`, scoped per user, and optionally per channel.
+///
+/// Code accesses it as `store.get(key)` and `store.set(key, value)`. Only booleans, numbers and strings can be
+/// stored, and setting a key to `nil` removes it. Store is saved only if code runs successfully. Runs on the
+/// same `LuaPool` which use the same store take turns, so that they don't overwrite each other's changes.
+#[derive(Clone)]
+pub struct LuaStore {
+    storage: Arc<dyn Storage>,
+    key: String,
+}
+
+impl LuaStore {
+    /// Store of a user. If `channel` is given, the user has a separate store in that channel.
+    pub fn new(storage: Arc<dyn Storage>, login: &str, channel: Option<&str>) -> LuaStore {
+        let key = match channel {
+            Some(channel) => format!("lua-store.{}.{}", channel, login),
+            None => format!("lua-store.{}", login),
+        };
+        LuaStore { storage, key }
+    }
+
+    fn load(&self) -> Result<StoreData, String> {
+        let values: BTreeMap<String, StoredValue> = match self.storage.load(&self.key) {
+            Ok(Some(bytes)) => toml::from_slice(&bytes).map_err(|err| {
+                error!("Store {} is corrupted: {}", self.key, err);
+                "store is corrupted".to_string()
+            })?,
+            Ok(None) => BTreeMap::new(),
+            Err(err) => {
+                error!("Failed to load store {}: {}", self.key, err);
+                return Err("failed to load store".to_string());
+            }
+        };
+        let size = values.iter().map(|(key, value)| key.len() + value.size()).sum();
+        Ok(StoreData {
+            values,
+            size,
+            modified: false,
+        })
+    }
+
+    fn save(&self, data: &StoreData) -> Result<(), String> {
+        let bytes = toml::to_vec(&data.values).map_err(|err| {
+            error!("Failed to serialize store {}: {}", self.key, err);
+            "failed to save store".to_string()
+        })?;
+        self.storage.save(&self.key, &bytes).map_err(|err| {
+            error!("Failed to save store {}: {}", self.key, err);
+            "failed to save store".to_string()
+        })
+    }
+}
+
+impl std::fmt::Debug for LuaStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LuaStore({})", self.key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+}
+
+impl StoredValue {
+    fn size(&self) -> usize {
+        match self {
+            StoredValue::String(s) => s.len(),
+            _ => 8,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StoreData {
+    values: BTreeMap<String, StoredValue>,
+    /// Size of keys and values, counted against `MAX_STORE_SIZE`.
+    size: usize,
+    modified: bool,
+}
+
+impl StoreData {
+    fn get<'lua>(&self, context: Context<'lua>, key: &str) -> Result<Value<'lua>, Error> {
+        Ok(match self.values.get(key) {
+            Some(StoredValue::Boolean(value)) => Value::Boolean(*value),
+            Some(StoredValue::Integer(value)) => Value::Integer(*value),
+            Some(StoredValue::Number(value)) => Value::Number(*value),
+            Some(StoredValue::String(value)) => Value::String(context.create_string(value)?),
+            None => Value::Nil,
+        })
+    }
+
+    fn set(&mut self, key: String, value: Value<'_>) -> Result<(), Error> {
+        let value = match value {
+            Value::Nil => None,
+            Value::Boolean(value) => Some(StoredValue::Boolean(value)),
+            Value::Integer(value) => Some(StoredValue::Integer(value)),
+            Value::Number(value) => Some(StoredValue::Number(value)),
+            Value::String(value) => Some(StoredValue::String(
+                value
+                    .to_str()
+                    .map_err(|_| Error::RuntimeError("only UTF-8 strings can be stored".to_string()))?
+                    .to_string(),
+            )),
+            _ => {
+                return Err(Error::RuntimeError(
+                    "only booleans, numbers and strings can be stored".to_string(),
+                ))
+            }
+        };
+
+        let old_size = self.values.get(&key).map_or(0, |old| key.len() + old.size());
+        let new_size = value.as_ref().map_or(0, |new| key.len() + new.size());
+
+        if new_size > 0 {
+            if key.len() > MAX_STORE_KEY_LENGTH {
+                return Err(Error::RuntimeError(format!(
+                    "store keys can't be longer than {} bytes",
+                    MAX_STORE_KEY_LENGTH
+                )));
+            }
+            if old_size == 0 && self.values.len() >= MAX_STORE_KEYS {
+                return Err(Error::RuntimeError(format!(
+                    "store can't have more than {} keys",
+                    MAX_STORE_KEYS
+                )));
+            }
+            if self.size - old_size + new_size > MAX_STORE_SIZE {
+                return Err(Error::RuntimeError(format!(
+                    "store can't be larger than {} bytes",
+                    MAX_STORE_SIZE
+                )));
+            }
+        }
+
+        self.size = self.size - old_size + new_size;
+        self.modified = true;
+        match value {
+            Some(value) => self.values.insert(key, value),
+            None => self.values.remove(&key),
+        };
+        Ok(())
+    }
+}
+
+/// Exposes store to code as `store` table.
+fn add_store<'lua>(context: Context<'lua>, env: &Table<'lua>, data: Arc<Mutex<StoreData>>) -> Result<(), Error> {
+    let store = context.create_table()?;
+
+    let get_data = data.clone();
+    store.set(
+        "get",
+        context.create_function(move |context, key: String| {
+            get_data
+                .lock()
+                .expect("lock is poisoned, but this shouldn't have happened")
+                .get(context, &key)
+        })?,
+    )?;
+
+    store.set(
+        "set",
+        context.create_function(move |_, (key, value): (String, Value)| {
+            data.lock()
+                .expect("lock is poisoned, but this shouldn't have happened")
+                .set(key, value)
+        })?,
+    )?;
+
+    env.set("store", store)
+}
+
+/// Names of emotes in order of their first appearance in the text.
+fn emote_names(text: &str, emotes: &[twitch::Emote]) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut positions: Vec<(usize, String)> = emotes
+        .iter()
+        .filter_map(|emote| {
+            let (start, end) = *emote.ranges.iter().min()?;
+            let name: String = chars.get(start..=end)?.iter().collect();
+            Some((start, name))
+        })
+        .collect();
+    positions.sort();
+    positions.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Xorshift random number generator. Not suitable for anything serious, but chat games.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        // generator is stuck at zero
+        Random(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Behaves like `math.random`: a float in [0, 1) without arguments, an integer in [1, m] or [m, n] otherwise.
+    fn generate<'lua>(&mut self, m: Option<i64>, n: Option<i64>) -> Result<Value<'lua>, Error> {
+        let (low, high) = match (m, n) {
+            (None, _) => return Ok(Value::Number((self.next() >> 11) as f64 / (1u64 << 53) as f64)),
+            (Some(m), None) => (1, m),
+            (Some(m), Some(n)) => (m, n),
+        };
+        if low > high {
+            return Err(Error::RuntimeError(
+                "bad argument to 'random' (interval is empty)".to_string(),
+            ));
+        }
+        let range = (high as i128 - low as i128 + 1) as u128;
+        Ok(Value::Integer(
+            (low as i128 + (self.next() as u128 % range) as i128) as i64,
+        ))
+    }
+}
+
+/// Exposes context and helper functions to code.
+fn add_context<'lua>(context: Context<'lua>, env: &Table<'lua>, lua_context: LuaContext) -> Result<(), Error> {
+    let ctx = context.create_table()?;
+    ctx.set("login", lua_context.login)?;
+    ctx.set("display_name", lua_context.display_name)?;
+    ctx.set(
+        "badges",
+        context.create_table_from(lua_context.badges.into_iter().map(|badge| (badge.name, badge.version)))?,
+    )?;
+    ctx.set("channel", lua_context.channel)?;
+    ctx.set("args", context.create_sequence_from(lua_context.args)?)?;
+    ctx.set("message_id", lua_context.message_id)?;
+    ctx.set("time", lua_context.time)?;
+    env.set("ctx", ctx)?;
+
+    let random = Mutex::new(Random::new(lua_context.seed));
+    let random = context.create_function(move |_, (m, n): (Option<i64>, Option<i64>)| {
+        random
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .generate(m, n)
+    })?;
+    env.set("random", random.clone())?;
+    env.get::<_, Table>("math")?.set("random", random)?;
+
+    let emotes = lua_context.emotes;
+    env.set(
+        "emotes",
+        context.create_function(move |context, ()| context.create_sequence_from(emotes.clone()))?,
+    )?;
+
+    let last_message = lua_context.last_message;
+    env.set(
+        "last_message",
+        context.create_function(move |_, ()| Ok(last_message.clone()))?,
+    )?;
+
+    Ok(())
+}
+
+/// Builds the environment untrusted code runs in, copying whitelisted parts of the standard library.
+fn safe_environment(context: Context<'_>) -> Result<Table<'_>, Error> {
+    let globals = context.globals();
+    let env = context.create_table()?;
+
+    for (library, names) in SAFE_LIBRARY.iter() {
+        let (source, target) = if library.is_empty() {
+            (globals.clone(), env.clone())
+        } else {
+            let target = context.create_table()?;
+            env.set(*library, target.clone())?;
+            (globals.get::<_, Table>(*library)?, target)
+        };
+        for name in names.iter() {
+            target.set(*name, source.get::<_, Value>(*name)?)?;
+        }
+    }
+
+    Ok(env)
+}
+
+/// Lua side of the sandbox. Untrusted code is passed to this chunk as an argument, so it is never a
+/// part of the trusted source and can only run inside `env`.
+fn sandbox() -> String {
     format!(
         r#"
-local env = {{}}
+local untrusted_code, env, start_counting = ...
+
+-- strings share a metatable, which leads to the string library. make it lead to the safe one instead
+getmetatable("").__index = env.string
+getmetatable("").__metatable = false
+
+-- library functions can't be interrupted, so make sure code can't spend long in any of them
+local function check_length(value, limit, what)
+  if type(value) == "string" and #value > limit then
+    error(what .. " is too long (limit is " .. limit .. " bytes)", 3)
+  end
+end
+
+-- each quantifier outside of escapes and sets can backtrack over the whole string, so count the ways they can stop
+local gsub = string.gsub
+local function check_complexity(s, pattern)
+  if type(s) ~= "string" or type(pattern) ~= "string" then
+    return
+  end
+  local items = gsub(gsub(pattern, "%%.", ""), "%[^?%]?[^%]]*%]", "")
+  local _, quantifiers = gsub(items, "[*+?-]", "")
+  local steps = 1
+  for i = 1, quantifiers + 1 do
+    steps = steps * (#s + i) / i
+    if steps > {max_steps} then
+      error("pattern is too complex for a string this long", 3)
+    end
+  end
+end
+
+local function limit_string_library(library)
+  for _, name in ipairs({{ "find", "match", "gmatch", "gsub" }}) do
+    local original = library[name]
+    library[name] = function(s, pattern, ...)
+      check_length(s, {max_subject_length}, "string")
+      check_length(pattern, {max_pattern_length}, "pattern")
+      check_complexity(s, pattern)
+      return original(s, pattern, ...)
+    end
+  end
+
+  local original_rep = library.rep
+  library.rep = function(s, n, ...)
+    if (tonumber(n) or 0) > {max_repetitions} then
+      error("too many repetitions (limit is {max_repetitions})", 2)
+    end
+    return original_rep(s, n, ...)
+  end
+end
+
+limit_string_library(env.string)
+limit_string_library(string)
+
+-- tables everyone has access to can't be tampered with on a raw level
+local shared = {{ [env] = true }}
+for _, value in pairs(env) do
+  if type(value) == "table" then
+    shared[value] = true
+  end
+end
+
+env.setmetatable = function(table, metatable)
+  if shared[table] then
+    error("cannot change metatable of a shared table", 2)
+  end
+  return setmetatable(table, metatable)
+end
+
+env.rawset = function(table, key, value)
+  if shared[table] then
+    error("cannot rawset into a shared table", 2)
+  end
+  return rawset(table, key, value)
+end
+
+env.load = function(chunk, name, _, chunk_env)
+  return load(chunk, name, 't', chunk_env or env)
+end
 
 local function run(untrusted_code)
   local untrusted_function, message = load(untrusted_code, nil, 't', env)
   if not untrusted_function then
     return {compilation_failed}, message
   end
+  -- everything above is trusted and doesn't count against the instruction limit
+  start_counting()
   local status, result = pcall(untrusted_function)
-  if result == nil then
-    return "nil"
+  if status then
+    return {success}, tostring(result)
   else
-    if status then
-      return {success}, result
-    else
-      return {runtime_error}, result
-    end
+    return {runtime_error}, tostring(result)
   end
 end
 
-return run([[
-    {code}
-]])
+return run(untrusted_code)
 "#,
-        code = untrusted_code,
         compilation_failed = ExecutionStatus::CompilationError as i32,
         runtime_error = ExecutionStatus::RuntimeError as i32,
         success = ExecutionStatus::Success as i32,
+        max_subject_length = MAX_PATTERN_SUBJECT_LENGTH,
+        max_pattern_length = MAX_PATTERN_LENGTH,
+        max_steps = MAX_PATTERN_STEPS,
+        max_repetitions = MAX_REPETITIONS,
     )
 }
 
@@ -90,20 +621,82 @@ fn strip_location(s: &str) -> &str {
     s
 }
 
+/// Tells running code to stop before it runs out of instructions.
+#[derive(Clone)]
+struct Interrupt {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    fn new(deadline: Option<Instant>) -> Interrupt {
+        Interrupt {
+            deadline,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Cancels code execution when dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
 /// Runs lua code in a sandbox.
 pub fn run_untrusted_lua_code(
     source_code: String,
+    lua_context: LuaContext,
+    instruction_limit: i32,
+    memory_limit: usize,
+) -> Result<SuccessfulExecution, String> {
+    run_interruptible(
+        source_code,
+        lua_context,
+        instruction_limit,
+        memory_limit,
+        Interrupt::new(None),
+    )
+}
+
+fn run_interruptible(
+    source_code: String,
+    lua_context: LuaContext,
     instruction_limit: i32,
     memory_limit: usize,
+    interrupt: Interrupt,
 ) -> Result<SuccessfulExecution, String> {
-    let source_code = sandbox(&source_code);
+    // store is loaded up front, so that code doesn't pay for it with its time
+    let store = lua_context.store.clone();
+    let store_data = match &store {
+        Some(store) => Some(Arc::new(Mutex::new(
+            store.load().map_err(|err| format!("ERROR: {}", err))?,
+        ))),
+        None => None,
+    };
+    let ref_store_data = store_data.clone();
 
-    let vm = rlua::Lua::new();
+    // only libraries code can use parts of are loaded, leaving the rest of the memory limit to code
+    let vm = rlua::Lua::new_with(StdLib::BASE | StdLib::STRING | StdLib::MATH | StdLib::TABLE | StdLib::UTF8);
 
-    let instructions = Arc::new(AtomicIsize::new(instruction_limit as isize));
+    // instructions are counted only once untrusted code starts, see `sandbox`
+    let instructions = Arc::new(AtomicIsize::new(std::isize::MAX));
     let ref_instructions = instructions.clone();
+    let start_instructions = instructions.clone();
     let timeout_raised = Arc::new(AtomicBool::new(false));
     let ref_timeout_raised = timeout_raised.clone();
+    let ref_interrupt = interrupt.clone();
 
     vm.set_memory_limit(Some(memory_limit));
 
@@ -116,24 +709,52 @@ pub fn run_untrusted_lua_code(
             if instructions.fetch_sub(1, Ordering::SeqCst) < 1 {
                 timeout_raised.store(true, Ordering::SeqCst);
                 Err(Error::RuntimeError("execution timeout!".to_string()))
+            } else if interrupt.is_cancelled() || interrupt.is_past_deadline() {
+                Err(Error::RuntimeError("execution interrupted!".to_string()))
             } else {
                 Ok(())
             }
         },
     );
 
-    vm.context(|context| match context.load(&source_code).into_function() {
-        Ok(compiled) => match compiled.call::<_, (ExecutionStatus, String)>(0) {
-            Ok((ExecutionStatus::Success, result)) => Ok(SuccessfulExecution {
-                instructions_left: ref_instructions.load(Ordering::SeqCst),
-                result: format!("{}", result),
-            }),
+    vm.context(|context| match context.load(&sandbox()).into_function() {
+        Ok(compiled) => match safe_environment(context)
+            .and_then(|env| add_context(context, &env, lua_context).map(|_| env))
+            .and_then(|env| match ref_store_data {
+                Some(data) => add_store(context, &env, data).map(|_| env),
+                None => Ok(env),
+            })
+            .and_then(|env| {
+                let start_counting = context.create_function(move |_, ()| {
+                    start_instructions.store(instruction_limit as isize, Ordering::SeqCst);
+                    Ok(())
+                })?;
+                // garbage left by setup shouldn't count against the memory limit either
+                vm.gc_collect()?;
+                compiled.call::<_, (ExecutionStatus, String)>((source_code, env, start_counting))
+            }) {
+            Ok((ExecutionStatus::Success, result)) => {
+                if let (Some(store), Some(data)) = (&store, &store_data) {
+                    let data = data.lock().expect("lock is poisoned, but this shouldn't have happened");
+                    if data.modified {
+                        store.save(&data).map_err(|err| format!("ERROR: {}", err))?;
+                    }
+                }
+                Ok(SuccessfulExecution {
+                    instructions_left: ref_instructions.load(Ordering::SeqCst),
+                    result: format!("{}", result),
+                })
+            }
             Ok((ExecutionStatus::CompilationError, s)) | Ok((ExecutionStatus::RuntimeError, s)) => {
                 Err(format!("ERROR: {}", strip_location(&s)))
             }
             Err(err) => {
                 if ref_timeout_raised.load(Ordering::SeqCst) {
                     Err("ERROR: instruction limit reached".to_string())
+                } else if ref_interrupt.is_cancelled() {
+                    Err("ERROR: execution cancelled".to_string())
+                } else if ref_interrupt.is_past_deadline() {
+                    Err("ERROR: time limit reached".to_string())
                 } else {
                     Err(format!("ERROR: {:?}", err))
                 }
@@ -143,11 +764,252 @@ pub fn run_untrusted_lua_code(
     })
 }
 
+/// Code to run on a pool's thread. Returns whether the thread should go on running other jobs.
+type Job = Box<dyn FnOnce() -> bool + Send>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Queued,
+    Running,
+    Done,
+    /// Nobody waits for the result anymore.
+    Abandoned,
+    /// Nobody waits for the result anymore, and another thread has been started in place of the one running the job.
+    Replaced,
+}
+
+/// A set of threads to run untrusted lua code on, so that it doesn't block async executor.
+///
+/// Only a limited number of scripts can wait for a free thread; scripts submitted beyond that are
+/// rejected right away. Threads are stopped when the pool is dropped.
+///
+/// Threads stuck running code nobody waits for anymore are replaced, but no more of them than the pool has
+/// threads. Until some of them finish, scripts are rejected as well.
+pub struct LuaPool {
+    jobs: Mutex<SyncSender<Job>>,
+    rx_job: Arc<Mutex<Receiver<Job>>>,
+    threads_started: AtomicUsize,
+    /// Threads which have been replaced, but still run their jobs.
+    threads_replaced: Arc<AtomicUsize>,
+    max_threads_replaced: usize,
+    /// Runs which use a store, by store key. A run is done with the store once its sender is dropped.
+    store_users: Mutex<HashMap<String, Vec<Shared<oneshot::Receiver<()>>>>>,
+}
+
+impl LuaPool {
+    /// Starts `threads` threads, with up to `queue_size` scripts waiting for them.
+    pub fn new(threads: usize, queue_size: usize) -> LuaPool {
+        let (tx_job, rx_job) = sync_channel(queue_size);
+
+        let pool = LuaPool {
+            jobs: Mutex::new(tx_job),
+            rx_job: Arc::new(Mutex::new(rx_job)),
+            threads_started: AtomicUsize::new(0),
+            threads_replaced: Arc::new(AtomicUsize::new(0)),
+            max_threads_replaced: threads,
+            store_users: Mutex::new(HashMap::new()),
+        };
+        for _ in 0..threads {
+            pool.start_thread();
+        }
+        pool
+    }
+
+    fn start_thread(&self) {
+        let n = self.threads_started.fetch_add(1, Ordering::SeqCst);
+        let rx_job = self.rx_job.clone();
+        thread::Builder::new()
+            .name(format!("lua-{}", n))
+            .spawn(move || worker_loop(rx_job))
+            .expect("Failed to start lua thread");
+    }
+
+    /// Registers a run which uses a store, returning runs which used it before.
+    fn use_store(&self, store: &LuaStore, done: oneshot::Receiver<()>) -> Vec<Shared<oneshot::Receiver<()>>> {
+        let mut store_users = self
+            .store_users
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened");
+        store_users.retain(|_, users| {
+            users.retain(|user| user.peek().is_none());
+            !users.is_empty()
+        });
+        let users = store_users.entry(store.key.clone()).or_insert_with(Vec::new);
+        let previous = users.clone();
+        users.push(done.shared());
+        previous
+    }
+
+    /// Runs lua code in a sandbox on one of the pool's threads.
+    ///
+    /// Besides instructions and memory, execution is limited in time, counting from the moment code is
+    /// submitted. Code stops running as soon as it executes its next instruction after the time is up,
+    /// or after the returned future is dropped. Library functions can't be interrupted, but the sandbox
+    /// keeps the time spent in them short; should code be stuck in one anyway, its thread is replaced
+    /// with a new one, and left to stop on its own.
+    ///
+    /// If code uses a store, it waits for code submitted earlier with the same store to finish first, and
+    /// this waiting counts against its time.
+    pub async fn run(
+        &self,
+        source_code: String,
+        lua_context: LuaContext,
+        instruction_limit: i32,
+        memory_limit: usize,
+        time_limit: Duration,
+    ) -> Result<SuccessfulExecution, String> {
+        let deadline = Instant::now() + time_limit;
+
+        // code sees the store as it was when it started, so it must start after others using the store are done
+        let (tx_done, rx_done) = oneshot::channel();
+        if let Some(store) = &lua_context.store {
+            let previous = self.use_store(store, rx_done);
+            if tokio::timer::Timeout::new_at(join_all(previous), deadline)
+                .await
+                .is_err()
+            {
+                return Err("ERROR: time limit reached".to_string());
+            }
+        }
+
+        let interrupt = Interrupt::new(Some(deadline));
+        let _cancel = CancelOnDrop(interrupt.cancelled.clone());
+
+        self.execute(deadline, move || {
+            // store is released once the job is over, even if nobody waits for it anymore
+            let _done = tx_done;
+            run_interruptible(source_code, lua_context, instruction_limit, memory_limit, interrupt)
+        })
+        .await
+    }
+
+    /// Runs `f` on one of the pool's threads, waiting for its result until `deadline`.
+    async fn execute<F>(&self, deadline: Instant, f: F) -> Result<SuccessfulExecution, String>
+    where
+        F: FnOnce() -> Result<SuccessfulExecution, String> + Send + 'static,
+    {
+        if self.threads_replaced.load(Ordering::SeqCst) >= self.max_threads_replaced {
+            warn!("Too many lua threads are stuck, rejecting lua code");
+            return Err("ERROR: too many scripts are running, try again later".to_string());
+        }
+
+        let state = Arc::new(Mutex::new(JobState::Queued));
+        let _abandon = AbandonOnDrop {
+            pool: self,
+            state: state.clone(),
+        };
+
+        let threads_replaced = self.threads_replaced.clone();
+        let (tx_result, rx_result) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            {
+                let mut state = state
+                    .lock()
+                    .expect("lock is poisoned, but this shouldn't have happened");
+                if *state == JobState::Abandoned {
+                    return true;
+                }
+                *state = JobState::Running;
+            }
+
+            let result = f();
+
+            let mut state = state
+                .lock()
+                .expect("lock is poisoned, but this shouldn't have happened");
+            match *state {
+                JobState::Abandoned => true,
+                JobState::Replaced => {
+                    // another thread has taken our place
+                    threads_replaced.fetch_sub(1, Ordering::SeqCst);
+                    false
+                }
+                _ => {
+                    *state = JobState::Done;
+                    let _ = tx_result.send(result);
+                    true
+                }
+            }
+        });
+
+        if let Err(err) = self
+            .jobs
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .try_send(job)
+        {
+            warn!("Failed to submit lua code: {}", err);
+            return Err("ERROR: too many scripts are running, try again later".to_string());
+        }
+
+        // slow library functions can't be interrupted, so we don't rely on code noticing the deadline
+        match tokio::timer::Timeout::new_at(rx_result, deadline).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("ERROR: execution cancelled".to_string()),
+            Err(_) => Err("ERROR: time limit reached".to_string()),
+        }
+    }
+}
+
+/// Abandons a job once nobody waits for its result. If the job is still running by then, it is likely stuck in a
+/// slow library function, so another thread is started in place of the one running it, unless too many have been
+/// replaced already.
+struct AbandonOnDrop<'a> {
+    pool: &'a LuaPool,
+    state: Arc<Mutex<JobState>>,
+}
+
+impl Drop for AbandonOnDrop<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .state
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened");
+        match *state {
+            JobState::Queued => *state = JobState::Abandoned,
+            JobState::Running => {
+                let replaced = self.pool.threads_replaced.fetch_add(1, Ordering::SeqCst);
+                if replaced < self.pool.max_threads_replaced {
+                    warn!("Lua code is still running, starting another thread in place of its one");
+                    *state = JobState::Replaced;
+                    self.pool.start_thread();
+                } else {
+                    warn!("Lua code is still running, but too many threads have been replaced already");
+                    self.pool.threads_replaced.fetch_sub(1, Ordering::SeqCst);
+                    *state = JobState::Abandoned;
+                }
+            }
+            JobState::Done | JobState::Abandoned | JobState::Replaced => {}
+        }
+    }
+}
+
+fn worker_loop(rx_job: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = rx_job
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .recv();
+        match job {
+            Ok(job) => {
+                if !job() {
+                    break;
+                }
+            }
+            // pool is dropped
+            Err(_) => break,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
+    use crate::irc;
+    use crate::storage::MemoryStorage;
+
     #[test]
     fn test_can_execute_normally() {
         let result = run_untrusted_lua_code(
@@ -159,6 +1021,7 @@ mod tests {
         return x
         "#
             .to_string(),
+            LuaContext::default(),
             100000,
             32 * (1 << 10),
         );
@@ -182,6 +1045,7 @@ mod tests {
         return x
         "#
             .to_string(),
+            LuaContext::default(),
             100,
             32 * (1 << 10),
         );
@@ -196,6 +1060,18 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_sandbox_setup_is_not_counted() {
+        let result = run_untrusted_lua_code("return 1".to_string(), LuaContext::default(), 1024, 32 * (1 << 10));
+
+        match result {
+            Ok(SuccessfulExecution { instructions_left, .. }) => {
+                assert!(instructions_left > 1000, "only {} instructions left", instructions_left)
+            }
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
     #[test]
     fn test_memory_limit_is_respected() {
         let result = run_untrusted_lua_code(
@@ -207,6 +1083,7 @@ mod tests {
         return x
         "#
             .to_string(),
+            LuaContext::default(),
             1000000,
             32 * (1 << 10),
         );
@@ -230,6 +1107,7 @@ mod tests {
         return x
         "#
             .to_string(),
+            LuaContext::default(),
             1000,
             32 * (1 << 10),
         );
@@ -241,4 +1119,649 @@ mod tests {
             Err(_) => assert!(true, "should abort with error"),
         };
     }
+
+    #[test]
+    fn test_code_cannot_escape_sandbox() {
+        // each of these would run outside of the sandbox if code was spliced into a string literal
+        let payloads = [
+            r#"]] return os.getenv("HOME") --[["#,
+            r#"]]..tostring(os)..[["#,
+            r#"]==] return io --[==["#,
+            r#"]] os.exit(1) --[["#,
+            "]]\nreturn load\n--[[",
+        ];
+
+        for payload in payloads.iter() {
+            match run_untrusted_lua_code(payload.to_string(), LuaContext::default(), 1000, 32 * (1 << 10)) {
+                Ok(SuccessfulExecution { result, .. }) => {
+                    assert!(
+                        false,
+                        "payload {:?} should not compile, returned '{}' instead",
+                        payload, result
+                    )
+                }
+                Err(_) => assert!(true, "should abort with error"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_globals_are_not_reachable() {
+        for global in ["os", "io", "require", "debug", "_G"].iter() {
+            let result = run_untrusted_lua_code(
+                format!("return {}", global),
+                LuaContext::default(),
+                1000,
+                32 * (1 << 10),
+            );
+
+            match result {
+                Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "nil", "{} is reachable", global),
+                Err(e) => assert!(false, "execution error: {}", e),
+            };
+        }
+    }
+
+    fn run(code: &str) -> Result<SuccessfulExecution, String> {
+        run_with_context(code, LuaContext::default())
+    }
+
+    fn run_with_context(code: &str, context: LuaContext) -> Result<SuccessfulExecution, String> {
+        run_untrusted_lua_code(code.to_string(), context, 10000, 256 * (1 << 10))
+    }
+
+    #[test]
+    fn test_safe_library_is_available() {
+        let result = run(r##"
+        local words = {}
+        for word in string.gmatch("b c a", "%a+") do
+            table.insert(words, word:upper())
+        end
+        table.sort(words)
+        return table.concat(words, ",") .. " " .. string.rep("x", 3) .. " " .. math.floor(2.5)
+            .. " " .. tostring(utf8.len("привет")) .. " " .. select("#", pairs({}))
+        "##);
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "A,B,C xxx 2 6 3"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_dangerous_functions_are_not_available() {
+        for code in [
+            "return string.dump",
+            "return (\"\").dump",
+            "return (\"\").pack",
+            "return (\"\").unpack",
+            "return (\"\").packsize",
+            "return collectgarbage",
+            "return print",
+            "return dofile",
+            "return coroutine",
+        ]
+        .iter()
+        {
+            match run(code) {
+                Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "nil", "`{}` is available", code),
+                Err(e) => assert!(false, "execution error: {}", e),
+            };
+        }
+
+        for code in ["return (\"\"):packsize(\"i4\")", "return (\"\"):unpack(\"s1\")"].iter() {
+            match run(code) {
+                Ok(SuccessfulExecution { result, .. }) => {
+                    assert!(
+                        false,
+                        "`{}` should abort with error, returned '{}' instead",
+                        code, result
+                    )
+                }
+                Err(_) => assert!(true, "should abort with error"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_binary_chunks_are_not_loaded() {
+        let result = run(r#"
+        local f, err = load(string.char(27) .. "Lua", nil, "b")
+        return err
+        "#);
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(result.contains("binary"), "unexpected result: {}", result)
+            }
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_text_chunks_are_loaded_into_sandbox() {
+        match run("return load(\"return os\")()") {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "nil"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_shared_tables_are_protected() {
+        for code in [
+            "setmetatable(string, {})",
+            "setmetatable(_ENV, {})",
+            "rawset(_ENV, \"x\", 1)",
+            "rawset(math, \"pi\", 3)",
+            "getmetatable(\"\").__index = {}",
+        ]
+        .iter()
+        {
+            match run(code) {
+                Ok(SuccessfulExecution { result, .. }) => {
+                    assert!(
+                        false,
+                        "`{}` should abort with error, returned '{}' instead",
+                        code, result
+                    )
+                }
+                Err(_) => assert!(true, "should abort with error"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_slow_string_functions_are_limited() {
+        for (code, error) in [
+            ("return string.rep('a', 3000):find('.-.-.-b')", "string is too long"),
+            ("return string.match('a', string.rep('.-', 40))", "pattern is too long"),
+            ("return string.rep('a', 1000):find('.-.-.-b')", "too complex"),
+            ("return ('a'):rep(200):gsub('%a*%a*%a*%a*b', '')", "too complex"),
+            ("for _ in ('a'):rep(2000):gmatch('a') do end", "string is too long"),
+            ("return (''):rep(1e18)", "too many repetitions"),
+        ]
+        .iter()
+        {
+            match run(code) {
+                Ok(SuccessfulExecution { result, .. }) => {
+                    assert!(
+                        false,
+                        "`{}` should abort with error, returned '{}' instead",
+                        code, result
+                    )
+                }
+                Err(e) => assert!(e.contains(error), "wrong error: {}", e),
+            };
+        }
+
+        // escaped characters and sets aren't quantifiers
+        match run("local a, b = string.rep('a-b+', 250):match('(%w+)%-(%w)[-+*?]') return a .. b") {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "ab"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_own_tables_can_have_metatables() {
+        let result = run(r#"
+        local t = setmetatable({}, { __index = function() return 42 end })
+        rawset(t, "x", 1)
+        return t.x + t.y
+        "#);
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "43"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new()
+            .build()
+            .expect("Failed to create runtime")
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_pool_runs_code() {
+        let pool = LuaPool::new(1, 1);
+
+        match block_on(pool.run(
+            "return 1 + 1".to_string(),
+            LuaContext::default(),
+            1000,
+            64 * (1 << 10),
+            Duration::from_secs(5),
+        )) {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "2"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_pool_respects_time_limit() {
+        let pool = LuaPool::new(1, 1);
+
+        let started = Instant::now();
+        let result = block_on(pool.run(
+            "while true do end".to_string(),
+            LuaContext::default(),
+            std::i32::MAX,
+            64 * (1 << 10),
+            Duration::from_millis(50),
+        ));
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should abort with error, returned '{}' instead", result)
+            }
+            Err(e) => assert_eq!(e, "ERROR: time limit reached", "wrong error"),
+        };
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "took too long: {:?}",
+            started.elapsed()
+        );
+
+        // thread is freed up once code notices the deadline
+        match block_on(pool.run(
+            "return 1".to_string(),
+            LuaContext::default(),
+            1000,
+            64 * (1 << 10),
+            Duration::from_secs(5),
+        )) {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "1"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    /// Stands in for code stuck in a slow library function, which doesn't notice the deadline.
+    fn stuck_for(duration: Duration) -> impl FnOnce() -> Result<SuccessfulExecution, String> + Send + 'static {
+        move || {
+            thread::sleep(duration);
+            Ok(SuccessfulExecution {
+                instructions_left: 0,
+                result: "done".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_pool_survives_stuck_code() {
+        let mut pool = LuaPool::new(1, 1);
+        // let code run again before the stuck thread is done
+        pool.max_threads_replaced = 2;
+
+        let result = block_on(pool.execute(
+            Instant::now() + Duration::from_millis(50),
+            stuck_for(Duration::from_secs(1)),
+        ));
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should abort with error, returned '{}' instead", result)
+            }
+            Err(e) => assert_eq!(e, "ERROR: time limit reached", "wrong error"),
+        };
+        assert_eq!(pool.threads_started.load(Ordering::SeqCst), 2);
+
+        match block_on(pool.run(
+            "return 1".to_string(),
+            LuaContext::default(),
+            1000,
+            64 * (1 << 10),
+            Duration::from_millis(500),
+        )) {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "1"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_pool_limits_stuck_threads() {
+        let pool = LuaPool::new(1, 1);
+
+        let result = block_on(pool.execute(
+            Instant::now() + Duration::from_millis(50),
+            stuck_for(Duration::from_millis(300)),
+        ));
+        assert!(result.is_err(), "should abort with error");
+
+        let run_code = || {
+            block_on(pool.run(
+                "return 1".to_string(),
+                LuaContext::default(),
+                1000,
+                64 * (1 << 10),
+                Duration::from_millis(500),
+            ))
+        };
+
+        match run_code() {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should be rejected, returned '{}' instead", result)
+            }
+            Err(e) => assert!(e.contains("too many scripts"), "wrong error: {}", e),
+        };
+
+        // once the stuck thread is done, code runs again
+        thread::sleep(Duration::from_millis(500));
+        match run_code() {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "1"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+        assert_eq!(pool.threads_started.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_pool_rejects_code_when_full() {
+        let pool = LuaPool::new(1, 1);
+
+        let result = block_on(async {
+            // one script occupies the thread, and another one waits for it
+            let busy = pool.run(
+                "while true do end".to_string(),
+                LuaContext::default(),
+                std::i32::MAX,
+                1 << 16,
+                Duration::from_millis(300),
+            );
+            let others = async {
+                tokio::timer::delay_for(Duration::from_millis(50)).await;
+                let waiting = pool.run(
+                    "return 1".to_string(),
+                    LuaContext::default(),
+                    1000,
+                    1 << 16,
+                    Duration::from_millis(300),
+                );
+                let rejected = pool.run(
+                    "return 2".to_string(),
+                    LuaContext::default(),
+                    1000,
+                    1 << 16,
+                    Duration::from_millis(300),
+                );
+                futures::join!(waiting, rejected).1
+            };
+            futures::join!(busy, others).1
+        });
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should be rejected, returned '{}' instead", result)
+            }
+            Err(e) => assert!(e.contains("too many scripts"), "wrong error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_dropped_execution_is_cancelled() {
+        let interrupt = Interrupt::new(None);
+        let cancel = CancelOnDrop(interrupt.cancelled.clone());
+
+        let handle = thread::spawn(move || {
+            run_interruptible(
+                "while true do end".to_string(),
+                LuaContext::default(),
+                std::i32::MAX,
+                1 << 16,
+                interrupt,
+            )
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(cancel);
+
+        match handle.join().expect("lua thread panicked") {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should abort with error, returned '{}' instead", result)
+            }
+            Err(e) => assert_eq!(e, "ERROR: execution cancelled", "wrong error"),
+        };
+    }
+
+    fn lua_context() -> LuaContext {
+        let message = irc::Message::parse(
+            "@badges=moderator/1,subscriber/12;display-name=Alice;emotes=25:0-4,12-16/1902:6-10;id=abc-123 \
+             :alice!alice@alice.tmi.twitch.tv PRIVMSG #test :Kappa Keepo Kappa",
+        )
+        .unwrap();
+        let event = twitch::Event::from_message(&message).unwrap();
+
+        LuaContext {
+            last_message: Some("hello".to_string()),
+            ..LuaContext::of(&event, "one two")
+        }
+    }
+
+    #[test]
+    fn test_context_describes_message() {
+        let context = lua_context();
+
+        assert_eq!(context.login, "alice");
+        assert_eq!(context.display_name, "Alice");
+        assert_eq!(context.channel, Some("test".to_string()));
+        assert_eq!(context.args, vec!["one".to_string(), "two".to_string()]);
+        assert_eq!(context.message_id, Some("abc-123".to_string()));
+        assert_eq!(context.emotes, vec!["Kappa".to_string(), "Keepo".to_string()]);
+        assert_eq!(context.badges.len(), 2);
+    }
+
+    #[test]
+    fn test_context_is_exposed_to_code() {
+        let result = run_with_context(
+            r#"
+            return table.concat({
+                ctx.login, ctx.display_name, ctx.channel, ctx.message_id, ctx.badges.subscriber,
+                table.concat(ctx.args, "+"), table.concat(emotes(), "+"), last_message(), tostring(ctx.time > 0),
+            }, " ")
+            "#,
+            lua_context(),
+        );
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert_eq!(result, "alice Alice test abc-123 12 one+two Kappa+Keepo hello true")
+            }
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_random_is_seeded_per_run() {
+        let code = "return random() .. ' ' .. random(10) .. ' ' .. math.random(-5, 5)";
+        let seeded = |seed| LuaContext {
+            seed,
+            ..LuaContext::default()
+        };
+
+        let first = run_with_context(code, seeded(1)).map(|execution| execution.result);
+        let same = run_with_context(code, seeded(1)).map(|execution| execution.result);
+        let other = run_with_context(code, seeded(2)).map(|execution| execution.result);
+
+        assert!(first.is_ok(), "execution error: {:?}", first.err());
+        assert_eq!(first, same, "same seed should give same numbers");
+        assert_ne!(first, other, "different seeds should give different numbers");
+    }
+
+    #[test]
+    fn test_random_respects_bounds() {
+        let result = run(r#"
+        for i = 1, 100 do
+            local x = random(3, 5)
+            assert(x >= 3 and x <= 5 and math.type(x) == "integer", "out of bounds: " .. x)
+            local y = random()
+            assert(y >= 0 and y < 1, "out of bounds: " .. y)
+        end
+        return "ok"
+        "#);
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "ok"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+
+        match run("return random(5, 3)") {
+            Ok(SuccessfulExecution { result, .. }) => {
+                assert!(false, "should abort with error, returned '{}' instead", result)
+            }
+            Err(e) => assert!(e.contains("interval is empty"), "wrong error: {}", e),
+        };
+    }
+
+    fn with_store(storage: &Arc<MemoryStorage>, login: &str, channel: Option<&str>) -> LuaContext {
+        LuaContext {
+            store: Some(LuaStore::new(storage.clone(), login, channel)),
+            ..LuaContext::default()
+        }
+    }
+
+    #[test]
+    fn test_store_keeps_values_between_runs() {
+        let storage = Arc::new(MemoryStorage::new());
+        let code = r#"
+        store.set("count", (store.get("count") or 0) + 1)
+        return store.get("count")
+        "#;
+
+        for expected in ["1", "2"].iter() {
+            match run_with_context(code, with_store(&storage, "alice", None)) {
+                Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, *expected),
+                Err(e) => assert!(false, "execution error: {}", e),
+            };
+        }
+
+        // other users and channel stores don't see it
+        let contexts = [
+            with_store(&storage, "bob", None),
+            with_store(&storage, "alice", Some("test")),
+            LuaContext::default(),
+        ];
+        for context in contexts.iter() {
+            match run_with_context("return store and store.get(\"count\")", context.clone()) {
+                Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "nil"),
+                Err(e) => assert!(false, "execution error: {}", e),
+            };
+        }
+    }
+
+    #[test]
+    fn test_concurrent_runs_keep_each_others_changes() {
+        let storage = Arc::new(MemoryStorage::new());
+        let pool = LuaPool::new(2, 2);
+        let code = r#"
+        local count = (store.get("count") or 0) + 1
+        for i=1,10000 do end
+        store.set("count", count)
+        return count
+        "#;
+        let run = || {
+            pool.run(
+                code.to_string(),
+                with_store(&storage, "alice", None),
+                std::i32::MAX,
+                64 * (1 << 10),
+                Duration::from_secs(5),
+            )
+        };
+
+        let results = block_on(async { futures::join!(run(), run()) });
+
+        let mut counts = Vec::new();
+        for result in vec![results.0, results.1] {
+            match result {
+                Ok(SuccessfulExecution { result, .. }) => counts.push(result),
+                Err(e) => assert!(false, "execution error: {}", e),
+            };
+        }
+        counts.sort();
+        assert_eq!(counts, vec!["1".to_string(), "2".to_string()]);
+    }
+
+    #[test]
+    fn test_store_preserves_types() {
+        let storage = Arc::new(MemoryStorage::new());
+
+        let _ = run_with_context(
+            r#"
+            store.set("b", true)
+            store.set("i", 42)
+            store.set("f", 0.5)
+            store.set("s", "return 1")
+            "#,
+            with_store(&storage, "alice", None),
+        );
+
+        let result = run_with_context(
+            r#"
+            return tostring(store.get("b")) .. " " .. math.type(store.get("i")) .. " "
+                .. math.type(store.get("f")) .. " " .. load(store.get("s"))()
+            "#,
+            with_store(&storage, "alice", None),
+        );
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "true integer float 1"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_store_is_not_saved_on_error() {
+        let storage = Arc::new(MemoryStorage::new());
+
+        let _ = run_with_context(
+            "store.set(\"x\", 1) error(\"boom\")",
+            with_store(&storage, "alice", None),
+        );
+
+        match run_with_context("return store.get(\"x\")", with_store(&storage, "alice", None)) {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "nil"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
+
+    #[test]
+    fn test_store_limits_are_enforced() {
+        let storage = Arc::new(MemoryStorage::new());
+
+        for (code, expected_error) in [
+            ("store.set(string.rep(\"k\", 100), 1)", "longer than"),
+            ("store.set(\"k\", string.rep(\"v\", 20000))", "larger than"),
+            ("for i = 1, 101 do store.set(tostring(i), i) end", "more than"),
+            ("store.set(\"k\", {})", "only booleans"),
+        ]
+        .iter()
+        {
+            match run_with_context(code, with_store(&storage, "alice", None)) {
+                Ok(SuccessfulExecution { result, .. }) => {
+                    assert!(
+                        false,
+                        "`{}` should abort with error, returned '{}' instead",
+                        code, result
+                    )
+                }
+                Err(e) => assert!(e.contains(expected_error), "wrong error: {}", e),
+            };
+        }
+
+        // values can be replaced and removed within limits
+        let result = run_with_context(
+            r#"
+            for i = 1, 100 do store.set(tostring(i), i) end
+            store.set("1", nil)
+            store.set("2", string.rep("v", 10000))
+            store.set("2", string.rep("v", 15000))
+            store.set("new", true)
+            return "ok"
+            "#,
+            with_store(&storage, "alice", None),
+        );
+
+        match result {
+            Ok(SuccessfulExecution { result, .. }) => assert_eq!(result, "ok"),
+            Err(e) => assert!(false, "execution error: {}", e),
+        };
+    }
 }
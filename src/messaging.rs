@@ -1,31 +1,116 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use async_std::net::TcpStream;
-use async_std::sync::Mutex;
+use chashmap::CHashMap;
 
-use async_tungstenite::{connect_async, MaybeTlsStream};
-
-use futures::channel::mpsc::{Receiver, Sender};
-use futures::stream::{SplitSink, SplitStream};
-use futures::{SinkExt, StreamExt};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use futures::{pin_mut, SinkExt, StreamExt};
 
 use log::*;
-use tungstenite::Message;
-use url::Url;
 
-use crate::banphrase::{BanphraseAPI, BanphraseResponse};
+use crate::banphrase::BanphraseChecker;
+use crate::connection::ConnectionError;
 use crate::cooldown::{CooldownState, CooldownTracker};
-use crate::executor::PreparedCommand;
+use crate::executor::{Overflow, PreparedCommand};
 use crate::history::History;
 use crate::irc;
-use crate::state::BotState;
-use crate::util::modify_message;
+use crate::ratelimit::TokenBucket;
+use crate::shutdown::ShutdownSignal;
+use crate::state::{BotState, ChannelRequest};
+use crate::twitch;
+use crate::util::{modify_message, sanitize_message, split_message, truncate_message};
+
+/// Twitch doesn't allow chat messages longer than this many characters.
+pub const MAX_MESSAGE_LENGTH: usize = 500;
+
+/// Longest text we send, leaving room for characters added to avoid duplicates and chat commands.
+const MAX_TEXT_LENGTH: usize = MAX_MESSAGE_LENGTH - 2;
+
+/// How many messages can wait to be sent to a channel.
+const LANE_CAPACITY: usize = 64;
+
+/// How long sending to a channel is suspended after server tells us we are rate limited.
+const RATELIMIT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Messages to channels suspended for longer than this are dropped instead of waiting.
+const MAX_SUSPENSION_WAIT: Duration = Duration::from_secs(30);
+
+/// Per-channel cooldown in channels where we are moderator or VIP.
+const PRIVILEGED_COOLDOWN: Duration = Duration::from_millis(100);
+
+/// Twitch counts messages sent from an account within windows of this length.
+const MESSAGE_WINDOW: Duration = Duration::from_secs(30);
+
+/// How many messages an account can send within a window.
+const MESSAGE_LIMIT: usize = 20;
+
+/// How many messages an account can send within a window to channels where it is moderator.
+const MODERATOR_MESSAGE_LIMIT: usize = 100;
+
+/// Twitch counts JOINs sent from an account within windows of this length.
+const JOIN_WINDOW: Duration = Duration::from_secs(10);
+
+/// How many JOINs an account can send within a window.
+pub(crate) const JOIN_LIMIT: usize = 20;
+
+/// Twitch counts whispers sent from an account within windows of these lengths.
+const WHISPER_BURST_WINDOW: Duration = Duration::from_secs(1);
+const WHISPER_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many whispers an account can send within a short and a long window respectively.
+const WHISPER_BURST_LIMIT: usize = 3;
+const WHISPER_LIMIT: usize = 100;
+
+/// Whispers are sent as a chat command to this channel.
+const WHISPER_CHANNEL: &str = "#jtv";
+
+pub(crate) enum Suspension {
+    Indefinite,
+    For(Duration),
+}
+
+/// Our status in a channel, which affects how fast we can send messages there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ChannelRole {
+    User,
+    Vip,
+    Moderator,
+}
+
+impl ChannelRole {
+    fn of(user: &twitch::User) -> ChannelRole {
+        if user.is_moderator() {
+            ChannelRole::Moderator
+        } else if user.has_badge("vip") {
+            ChannelRole::Vip
+        } else {
+            ChannelRole::User
+        }
+    }
+}
 
 pub(crate) struct MessagingState {
     pub cooldowns: CooldownTracker<String>,
     pub history: History<String>,
-    pub banphrase_api: BanphraseAPI,
+    pub banphrase: Box<dyn BanphraseChecker>,
+    /// Account-wide message counter.
+    pub messages: TokenBucket,
+    /// Account-wide JOIN counter.
+    pub joins: TokenBucket,
+    /// Account-wide whisper counters.
+    whisper_bursts: TokenBucket,
+    whispers: TokenBucket,
+    default_cooldown: Duration,
+    roles: CHashMap<String, ChannelRole>,
+    /// Channels we shouldn't send messages to until a certain moment (or at all, if `None`).
+    suspensions: CHashMap<String, Option<Instant>>,
+    /// Last message sent to each channel, which is retried if server rejects it.
+    last_sent: CHashMap<String, PreparedMessage>,
+    /// Channels we are going to leave once messages queued for them are sent.
+    leaving: CHashMap<String, ()>,
 }
 
 impl MessagingState {
@@ -33,12 +118,146 @@ impl MessagingState {
         channels: &Vec<String>,
         initial_cooldown: Duration,
         history_ttl: Duration,
-        banphrase_api_url: String,
+        banphrase: Box<dyn BanphraseChecker>,
     ) -> MessagingState {
         MessagingState {
             cooldowns: CooldownTracker::new(channels.iter().map(|c| (c.to_string(), initial_cooldown)).collect()),
             history: History::new(channels.iter().map(|c| c.to_string()).collect(), history_ttl),
-            banphrase_api: BanphraseAPI::new(banphrase_api_url),
+            banphrase,
+            messages: TokenBucket::new(MESSAGE_WINDOW),
+            joins: TokenBucket::new(JOIN_WINDOW),
+            whisper_bursts: TokenBucket::new(WHISPER_BURST_WINDOW),
+            whispers: TokenBucket::new(WHISPER_WINDOW),
+            default_cooldown: initial_cooldown,
+            roles: CHashMap::new(),
+            suspensions: CHashMap::new(),
+            last_sent: CHashMap::new(),
+            leaving: CHashMap::new(),
+        }
+    }
+
+    /// Starts tracking a channel we have just joined. If we were going to leave it, we no longer are.
+    pub fn add_channel(&self, channel: &str) {
+        self.leaving.remove(channel);
+        if !self.cooldowns.contains(&channel.to_string()) {
+            self.cooldowns
+                .add_channel(channel.to_string(), self.default_cooldown, true);
+        }
+        self.history.add_channel(channel.to_string());
+    }
+
+    /// Marks a channel to be left once messages queued for it are sent.
+    pub fn leave_channel(&self, channel: &str) {
+        self.leaving.insert(channel.to_string(), ());
+    }
+
+    /// Forgets everything we know about a channel we have left.
+    pub fn remove_channel(&self, channel: &str) {
+        self.cooldowns.remove_channel(&channel.to_string());
+        self.history.remove_channel(channel);
+        self.roles.remove(channel);
+        self.suspensions.remove(channel);
+        self.last_sent.remove(channel);
+    }
+
+    /// Updates our role in a channel, adjusting cooldowns accordingly.
+    pub fn update_role(&self, channel: &str, role: ChannelRole) {
+        let previous = self
+            .roles
+            .insert(channel.to_string(), role)
+            .unwrap_or(ChannelRole::User);
+        if previous == role {
+            return;
+        }
+
+        let cooldown = match role {
+            ChannelRole::Moderator | ChannelRole::Vip => PRIVILEGED_COOLDOWN,
+            ChannelRole::User => self.default_cooldown,
+        };
+        info!(
+            "Updated cooldown to {:?} for channel {} because our role changed from {:?} to {:?}",
+            cooldown, channel, previous, role
+        );
+        self.cooldowns.update(&channel.to_string(), cooldown);
+    }
+
+    /// Waits until account-wide limits allow us to send a message to a channel.
+    pub async fn acquire_message_slot(&self, channel: &str) {
+        let limit = match self.roles.get(channel).map(|role| *role) {
+            Some(ChannelRole::Moderator) => MODERATOR_MESSAGE_LIMIT,
+            _ => MESSAGE_LIMIT,
+        };
+        self.messages.acquire(limit).await;
+    }
+
+    /// Waits until account-wide limits allow us to send a whisper.
+    pub async fn acquire_whisper_slot(&self) {
+        self.whispers.acquire(WHISPER_LIMIT).await;
+        self.whisper_bursts.acquire(WHISPER_BURST_LIMIT).await;
+    }
+
+    /// Suspends sending messages to a channel for a given duration, or indefinitely if it is `None`.
+    pub fn suspend(&self, channel: &str, duration: Option<Duration>) {
+        self.suspensions
+            .insert(channel.to_string(), duration.map(|d| Instant::now() + d));
+    }
+
+    /// Checks whether sending messages to a channel is currently suspended.
+    pub fn suspension(&self, channel: &str) -> Option<Suspension> {
+        let now = Instant::now();
+        match *self.suspensions.get(channel)? {
+            Some(until) if until > now => Some(Suspension::For(until - now)),
+            Some(_) => None,
+            None => Some(Suspension::Indefinite),
+        }
+    }
+
+    /// Takes the last message sent to a channel for retrying, unless it was a retry itself.
+    fn take_for_retry(&self, channel: &str) -> Option<PreparedMessage> {
+        self.last_sent
+            .remove(channel)
+            .filter(|message| !message.is_retry)
+            .map(|message| PreparedMessage {
+                is_retry: true,
+                ..message
+            })
+    }
+
+    /// Reacts to a NOTICE with `msg-id` tag sent by the server regarding our messages.
+    ///
+    /// If the rejected message should be sent again, it is returned.
+    fn handle_notice(&self, channel: &str, msg_id: &str, text: &str) -> Option<PreparedMessage> {
+        match msg_id {
+            "msg_duplicate" => {
+                info!("Message to {} was rejected as duplicate, retrying", channel);
+                self.take_for_retry(channel)
+            }
+            "msg_ratelimit" => {
+                warn!("Rate limited in {}, backing off for {:?}", channel, RATELIMIT_BACKOFF);
+                self.suspend(channel, Some(RATELIMIT_BACKOFF));
+                self.take_for_retry(channel)
+            }
+            "msg_timedout" => {
+                // "You are timed out for N more seconds."
+                let duration = text
+                    .split_whitespace()
+                    .filter_map(|word| word.parse::<u64>().ok())
+                    .next()
+                    .map(Duration::from_secs)
+                    .unwrap_or(RATELIMIT_BACKOFF);
+                warn!("Timed out in {} for {:?}", channel, duration);
+                self.suspend(channel, Some(duration));
+                None
+            }
+            "msg_banned" | "msg_channel_suspended" => {
+                warn!("Cannot send messages to {} anymore: {}", channel, text);
+                self.suspend(channel, None);
+                None
+            }
+            _ => {
+                info!("NOTICE in {} ({}): {}", channel, msg_id, text);
+                None
+            }
         }
     }
 }
@@ -47,257 +266,938 @@ impl MessagingState {
 pub enum Action {
     ExecuteCommand(PreparedCommand),
     SendMessage(String),
+    RetryMessage(PreparedMessage),
+    Reconnect,
     None,
 }
 
+/// Senders of the queues which incoming messages are dispatched to.
+#[derive(Clone)]
+pub(crate) struct Queues {
+    pub tx_line: Sender<String>,
+    pub tx_message: Sender<PreparedMessage>,
+    pub tx_command: Sender<PreparedCommand>,
+}
+
 #[derive(Debug, Clone)]
 pub struct PreparedMessage {
+    /// Channel to send the message to, or login of the user to whisper it to.
     pub channel: String,
     pub message: String,
+    pub(crate) overflow: Overflow,
+    pub(crate) priority: bool,
+    /// Id of the message this one replies to, if any.
+    pub(crate) reply_to: Option<String>,
+    pub(crate) whisper: bool,
+    /// Instead of sending a message, leave the channel once messages queued before this one are sent.
+    pub(crate) leave: bool,
+    pub(crate) is_retry: bool,
 }
 
-type WebSocketStreamSink = async_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
-
-type WebSocketSharedSink = Arc<Mutex<SplitSink<WebSocketStreamSink, Message>>>;
-
-type WebSocketStream = SplitStream<WebSocketStreamSink>;
-
-/// This function initializes messaging stream.
-pub(crate) async fn initialize(
-    url: Url,
-    username: &str,
-    password: &str,
-    channels: impl Iterator<Item = &String>,
-) -> WebSocketStreamSink {
-    info!("Connecting to {}...", url);
-    let (mut ws_stream, _) = connect_async(url).await.expect("Failed to connect to socket");
-
-    info!(
-        "Authenticating with user name '{}', oauth token '{}'",
-        username, "*".repeat(password.len())
-    );
-
-    // login to twitch IRC
-    ws_stream
-        .send(Message::Text(format!("PASS oauth:{}", password)))
-        .await
-        .expect("Failed to send WS message");
-    ws_stream
-        .send(Message::Text(format!("NICK {}", username)))
-        .await
-        .expect("Failed to send WS message");
-    ws_stream
-        .send(Message::Text(
-            "CAP REQ :twitch.tv/tags twitch.tv/commands twitch.tv/membership".to_owned(),
-        ))
-        .await
-        .expect("Failed to send WS message");
-
-    // join channels
-    for channel in channels {
-        info!("Joining channel: {}", channel);
-        ws_stream
-            .send(Message::Text(format!("JOIN #{}", channel)))
-            .await
-            .expect("Failed to send WS message");
-    }
-
-    ws_stream
+impl PreparedMessage {
+    fn leave(channel: String) -> PreparedMessage {
+        PreparedMessage {
+            channel,
+            message: String::new(),
+            overflow: Overflow::Truncate,
+            priority: false,
+            reply_to: None,
+            whisper: false,
+            leave: true,
+            is_retry: false,
+        }
+    }
 }
 
-/// This function acts as event loop for reading messages from socket.
-pub(crate) async fn receiver_event_loop<T: 'static + Send + Sync>(
-    rx_socket: WebSocketStream,
-    tx_socket: WebSocketSharedSink,
-    tx_command: Sender<PreparedCommand>,
+/// Decides what to do in response to a single raw IRC message received from the server.
+fn decide_action<T: 'static + Send + Sync>(
+    raw_message: &str,
+    state: &BotState<T>,
+    messaging_state: &MessagingState,
+) -> Action {
+    let message = match irc::Message::parse(raw_message) {
+        Ok(message) => message,
+        Err(err) => {
+            error!("Error parsing message: {} (message = {})", err, raw_message);
+            return Action::None;
+        }
+    };
+
+    let event = match twitch::Event::from_message(&message) {
+        Ok(event) => event,
+        Err(err) => {
+            error!("Unexpected message: {} (message = {})", err, raw_message);
+            return Action::None;
+        }
+    };
+
+    match event {
+        twitch::Event::Privmsg(_) | twitch::Event::Whisper(_) => match state.try_convert_to_command(&message) {
+            Some(command) => Action::ExecuteCommand(PreparedCommand {
+                message: message.to_owned_message(),
+                event,
+                command,
+            }),
+            None => {
+                info!("{}", message);
+                if let twitch::Event::Privmsg(privmsg) = event {
+                    state.chat.push(&privmsg.channel, &privmsg.user.login, &privmsg.text);
+                }
+                Action::None
+            }
+        },
+        twitch::Event::Ping(token) => {
+            info!("Responding to PING...");
+            Action::SendMessage(
+                irc::MessageBuilder::new("PONG")
+                    .with_trailing(token.as_ref().map(|s| s.as_str()).unwrap_or(""))
+                    .string(),
+            )
+        }
+        twitch::Event::Pong => {
+            trace!("Received PONG");
+            Action::None
+        }
+        twitch::Event::Reconnect => {
+            info!("Server asked us to reconnect");
+            Action::Reconnect
+        }
+        twitch::Event::Notice(notice) => match (notice.channel, notice.msg_id) {
+            (Some(channel), Some(msg_id)) => match messaging_state.handle_notice(&channel, &msg_id, &notice.text) {
+                Some(message) => Action::RetryMessage(message),
+                None => Action::None,
+            },
+            _ => {
+                info!("NOTICE: {}", notice.text);
+                Action::None
+            }
+        },
+        twitch::Event::Join { channel, login } if login == state.username => {
+            info!("Joined channel: {}", channel);
+            Action::None
+        }
+        twitch::Event::Part { channel, login } if login == state.username => {
+            info!("Left channel: {}", channel);
+            Action::None
+        }
+        twitch::Event::UserState(user_state) => {
+            info!("Received USERSTATE: {}", raw_message);
+            messaging_state.update_role(&user_state.channel, ChannelRole::of(&user_state.user));
+            Action::None
+        }
+        event => {
+            info!("No handler for event {:?}", event);
+            Action::None
+        }
+    }
+}
+
+/// Handles a single raw IRC message received from the server.
+///
+/// Returns an error if the server wants us to reconnect.
+pub(crate) async fn handle_message<T: 'static + Send + Sync>(
+    raw_message: &str,
+    queues: &mut Queues,
+    state: &BotState<T>,
+    messaging_state: &MessagingState,
+) -> Result<(), ConnectionError> {
+    match decide_action(raw_message, state, messaging_state) {
+        Action::ExecuteCommand(command) => {
+            if let Err(err) = queues.tx_command.send(command).await {
+                error!("Failed to submit command: {}", err);
+            }
+        }
+        Action::SendMessage(message) => {
+            if let Err(err) = queues.tx_line.send(message).await {
+                error!("Failed to submit message: {}", err);
+            }
+        }
+        Action::RetryMessage(message) => {
+            if let Err(err) = queues.tx_message.send(message).await {
+                error!("Failed to resubmit message: {}", err);
+            }
+        }
+        Action::Reconnect => return Err(ConnectionError::Reconnect),
+        Action::None => trace!("No action taken"),
+    }
+
+    Ok(())
+}
+
+/// This function acts as event loop for joining and leaving channels at runtime.
+///
+/// Per-channel state is created before JOIN is sent. Channels are left only after messages already queued
+/// for them are sent, such as the reply to a command asking us to leave, and their state is torn down then.
+pub(crate) async fn membership_event_loop<T: 'static + Send + Sync>(
+    rx_request: Receiver<ChannelRequest>,
+    tx_line: Sender<String>,
+    tx_message: Sender<PreparedMessage>,
     state: Arc<BotState<T>>,
     messaging_state: Arc<MessagingState>,
+    shutdown: ShutdownSignal,
 ) {
-    let mut rx_socket = rx_socket;
-    let mut tx_command = tx_command;
-
-    while let Some(message) = rx_socket.next().await {
-        match message {
-            Ok(Message::Text(message)) => {
-                for raw_message in message.split_terminator("\r\n") {
-                    match irc::Message::parse(raw_message) {
-                        Ok(message) => {
-                            let action = match message.command.name {
-                                "PRIVMSG" => {
-                                    if let Some(command) = state.try_convert_to_command(&message) {
-                                        Action::ExecuteCommand(PreparedCommand {
-                                            message: raw_message.to_string(),
-                                            command,
-                                        })
-                                    } else {
-                                        info!("{}", message);
-                                        Action::None
-                                    }
-                                }
-                                "PING" => {
-                                    info!("Responding to PING...");
-                                    Action::SendMessage(
-                                        irc::MessageBuilder::new("PONG")
-                                            .with_trailing(message.trailing.unwrap_or(""))
-                                            .string(),
-                                    )
-                                }
-                                "USERSTATE" => {
-                                    const MODERATOR_CD: Duration = Duration::from_millis(100);
-
-                                    let channel = message.first_arg_as_channel_name().unwrap().to_string();
-                                    info!("Received USERSTATE: {}", raw_message);
-
-                                    for badge in message.tag_value("badges").unwrap_or("").split_terminator(',') {
-                                        if badge.starts_with("moderator") {
-                                            info!(
-                                                "Updated cooldown to {:?} for channel {} because of moderator status",
-                                                MODERATOR_CD, channel
-                                            );
-                                            messaging_state.cooldowns.update(&channel, MODERATOR_CD);
-                                        }
-                                    }
-                                    Action::None
-                                }
-                                cmd => {
-                                    info!("No handler for command {} / {}", cmd, message);
-                                    Action::None
-                                }
-                            };
-
-                            match action {
-                                Action::ExecuteCommand(command) => {
-                                    tx_command.send(command).await.expect("Failed to submit command")
-                                }
-                                Action::SendMessage(message) => tx_socket
-                                    .lock()
-                                    .await
-                                    .send(Message::text(message))
-                                    .await
-                                    .expect("Failed to send message"),
-                                Action::None => trace!("No action taken"),
-                            }
-                        }
-                        Err(err) => error!("Error parsing message: {} (message = {})", err, message),
-                    }
+    let mut rx_request = rx_request;
+    let mut tx_line = tx_line;
+    let mut tx_message = tx_message;
+
+    loop {
+        let request = rx_request.next();
+        let stopped = shutdown.wait();
+        pin_mut!(stopped);
+        let request = match select(request, stopped).await {
+            Either::Left((Some(request), _)) => request,
+            Either::Left((None, _)) | Either::Right(_) => break,
+        };
+
+        match request {
+            ChannelRequest::Join(channel) => {
+                if !state.channels.write().await.insert(channel.clone()) {
+                    info!("Already in channel {}, not joining", channel);
+                    continue;
+                }
+                messaging_state.add_channel(&channel);
+                messaging_state.joins.acquire(JOIN_LIMIT).await;
+                info!("Joining channel: {}", channel);
+                if let Err(err) = tx_line.send(format!("JOIN #{}", channel)).await {
+                    error!("Failed to submit JOIN: {}", err);
+                }
+            }
+            ChannelRequest::Part(channel) => {
+                if !state.channels.write().await.remove(&channel) {
+                    info!("Not in channel {}, not leaving", channel);
+                    continue;
+                }
+                state.chat.remove_channel(&channel);
+                messaging_state.leave_channel(&channel);
+                if let Err(err) = tx_message.send(PreparedMessage::leave(channel)).await {
+                    error!("Failed to submit PART: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Leaves a channel and forgets about it, unless it has been joined again since it was asked to be left.
+async fn leave(channel: String, state: &MessagingState, tx_line: &mut Sender<String>) {
+    if state.leaving.remove(&channel).is_none() {
+        info!("Channel {} was joined again, not leaving", channel);
+        return;
+    }
+    state.remove_channel(&channel);
+
+    info!("Leaving channel: {}", channel);
+    if let Err(err) = tx_line.send(format!("PART #{}", channel)).await {
+        error!("Failed to submit PART: {}", err);
+    }
+}
+
+/// Sends a single message to its channel, once it is allowed to.
+async fn deliver(prepared: PreparedMessage, state: &MessagingState, tx_line: &mut Sender<String>) {
+    let PreparedMessage { message, channel, .. } = prepared.clone();
+
+    // check whether server allows us to send messages to this channel
+    match state.suspension(&channel) {
+        Some(Suspension::Indefinite) => {
+            info!(
+                "Sending messages to {} is suspended -- not sending ({})",
+                channel, message
+            );
+            return;
+        }
+        Some(Suspension::For(how_long)) if how_long > MAX_SUSPENSION_WAIT => {
+            info!(
+                "Sending messages to {} is suspended for {:?} -- not sending ({})",
+                channel, how_long, message
+            );
+            return;
+        }
+        Some(Suspension::For(how_long)) => tokio::timer::delay_for(how_long).await,
+        None => {}
+    }
+
+    // bring message to its final form before anything else: banphrase checker has to see exactly
+    // what is going to be sent
+    let text = sanitize_message(&message);
+    if text.is_empty() {
+        info!("Message to {} is empty -- not sending", channel);
+        return;
+    }
+
+    let parts = if text.chars().count() <= MAX_TEXT_LENGTH {
+        vec![text.clone()]
+    } else {
+        match prepared.overflow {
+            Overflow::Truncate => vec![truncate_message(&text, MAX_TEXT_LENGTH)],
+            // any word can start a part, so each of them has to be made safe on its own
+            Overflow::Split => split_message(&text, MAX_TEXT_LENGTH)
+                .iter()
+                .map(|part| sanitize_message(part))
+                .collect(),
+        }
+    };
+
+    // consult history to find out whether we need to modify messages to avoid them being duplicates.
+    // messages are remembered right away, so that identical messages sent concurrently are told apart
+    let mut texts = Vec::with_capacity(parts.len());
+    for part in parts.iter() {
+        let mut text = part.clone();
+        match state.history.contains(&channel, &text).await {
+            Some(0) => state.history.push(&channel, text.clone()).await,
+            Some(n) => modify_message(&mut text, n - 1),
+            None => {
+                error!("No such channel: {}", channel);
+                return;
+            }
+        }
+        texts.push(text);
+    }
+
+    // consult cooldown tracker and/or banphrase checker. all parts are checked before the first one is
+    // sent, and so is the whole message, so that banned phrase doesn't slip through split between parts
+    let mut checked: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+    if texts.len() > 1 {
+        checked.push(&text);
+    }
+    let banphrase_future =
+        futures::future::join_all(checked.iter().map(|text| state.banphrase.is_banned(&channel, text)));
+    let responses = match state.cooldowns.access_raw(&channel) {
+        Some(read_lock) => {
+            // let's simply check for cooldown first
+            match read_lock.cooldown() {
+                CooldownState::Ready => {
+                    // if this is ready, we don't really care -- we need to check banphrase
+                    // api first.
+                    banphrase_future.await
+                }
+                CooldownState::NotReady(how_long) => {
+                    // if this is not ready, we can align banphrase api request and waiting
+                    // time.
+                    futures::future::join(tokio::timer::delay_for(how_long), banphrase_future)
+                        .await
+                        .1
+                }
+            }
+        }
+        None => {
+            error!("No such channel: {}", channel);
+            return;
+        }
+    };
+
+    // now that we've got response from banphrase checker, lets check it
+    for (text, response) in checked.iter().zip(responses) {
+        match response {
+            Ok(true) => {
+                info!(
+                    "Banphrase checker says that message is banned -- not sending ({})",
+                    text
+                );
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to consult banphrase checker: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    let target = format!("#{}", channel);
+    for (part, text) in parts.into_iter().zip(texts) {
+        // bu-u-ut here we need to consult cooldown tracker again to find out whether we can
+        // send this message. this also keeps parts of the message apart
+        loop {
+            let cooldown = match state.cooldowns.access_raw(&channel) {
+                Some(read_lock) => read_lock.try_reset(),
+                None => {
+                    error!("No such channel: {}", channel);
+                    return;
                 }
+            };
+            match cooldown {
+                CooldownState::NotReady(how_long) => tokio::timer::delay_for(how_long).await,
+                CooldownState::Ready => break,
+            }
+        }
+
+        // and finally, make sure we don't exceed account-wide limits. this waits (rather than
+        // drops the message) until we are allowed to send
+        state.acquire_message_slot(&channel).await;
+
+        // text is sent exactly as it was checked
+        let line = match &prepared.reply_to {
+            Some(id) => irc::MessageBuilder::new("PRIVMSG")
+                .with_tag("reply-parent-msg-id", Some(id))
+                .with_arg(&target)
+                .with_trailing(&text)
+                .string(),
+            None => irc::MessageBuilder::new("PRIVMSG")
+                .with_arg(&target)
+                .with_trailing(&text)
+                .string(),
+        };
+
+        info!("Sending message: {:?}", line);
+
+        state.last_sent.insert(
+            channel.clone(),
+            PreparedMessage {
+                message: part,
+                ..prepared.clone()
+            },
+        );
+
+        if let Err(err) = tx_line.send(line).await {
+            error!("Failed to submit message: {}", err);
+        }
+    }
+}
+
+/// Sends a single whisper. Whispers aren't seen in chat, so channel cooldowns and banphrases don't apply
+/// to them, only account-wide whisper limits.
+async fn deliver_whisper(prepared: PreparedMessage, state: &MessagingState, tx_line: &mut Sender<String>) {
+    let PreparedMessage {
+        channel: login,
+        message,
+        overflow,
+        ..
+    } = prepared;
+
+    let text = sanitize_message(&message);
+    if text.is_empty() {
+        info!("Whisper to {} is empty -- not sending", login);
+        return;
+    }
+
+    // the whole chat command has to fit into a message
+    let command = format!("/w {} ", login);
+    let limit = MAX_MESSAGE_LENGTH - command.chars().count();
+    let parts = if text.chars().count() <= limit {
+        vec![text]
+    } else {
+        match overflow {
+            Overflow::Truncate => vec![truncate_message(&text, limit)],
+            Overflow::Split => split_message(&text, limit),
+        }
+    };
+
+    for part in parts {
+        state.acquire_whisper_slot().await;
+
+        let text = format!("{}{}", command, part);
+        let line = irc::MessageBuilder::new("PRIVMSG")
+            .with_arg(WHISPER_CHANNEL)
+            .with_trailing(&text)
+            .string();
+
+        info!("Sending whisper: {:?}", line);
+
+        if let Err(err) = tx_line.send(line).await {
+            error!("Failed to submit whisper: {}", err);
+        }
+    }
+}
+
+/// Messages waiting to be sent to a channel.
+struct Lane {
+    normal: Sender<PreparedMessage>,
+    priority: Sender<PreparedMessage>,
+    /// Completes once the lane has stopped.
+    done: oneshot::Receiver<()>,
+}
+
+/// Sends messages to a channel one at a time, in the order they were queued. Priority messages go ahead of
+/// all normal ones which are still waiting.
+///
+/// Lane starts once the `previous` one (if any) is done, and stops once both its queues are closed.
+async fn lane_loop(
+    mut normal: Receiver<PreparedMessage>,
+    mut priority: Receiver<PreparedMessage>,
+    previous: Option<oneshot::Receiver<()>>,
+    _done: oneshot::Sender<()>,
+    mut tx_line: Sender<String>,
+    state: Arc<MessagingState>,
+) {
+    if let Some(previous) = previous {
+        let _ = previous.await;
+    }
+
+    // closed receivers must not be polled again
+    let (mut priority_open, mut normal_open) = (true, true);
+    while priority_open || normal_open {
+        let next = if !priority_open {
+            normal.next().await
+        } else if !normal_open {
+            priority.next().await
+        } else {
+            match priority.try_next() {
+                Ok(message) => message,
+                Err(_) => match select(priority.next(), normal.next()).await {
+                    Either::Left((message, _)) => message,
+                    Either::Right((None, _)) => {
+                        normal_open = false;
+                        continue;
+                    }
+                    Either::Right((message, _)) => message,
+                },
             }
-            Ok(message) => error!("Unsupported message: {:?}", message),
-            Err(err) => error!("Received error: {:?}", err),
+        };
+
+        match next {
+            Some(message) if message.leave => leave(message.channel, &state, &mut tx_line).await,
+            Some(message) if message.whisper => deliver_whisper(message, &state, &mut tx_line).await,
+            Some(message) => deliver(message, &state, &mut tx_line).await,
+            None if priority_open => priority_open = false,
+            None => normal_open = false,
         }
     }
 }
 
 /// This function acts as event loop for sending messages to socket.
+///
+/// Each channel gets its own lane, so messages to a channel are sent in order, while channels don't
+/// wait for each other. Lane of a channel is closed once we are asked to leave it.
 pub(crate) async fn sender_event_loop(
-    rx_message: Receiver<PreparedMessage>,
-    tx_socket: WebSocketSharedSink,
+    mut rx_message: Receiver<PreparedMessage>,
+    tx_line: Sender<String>,
     state: Arc<MessagingState>,
-    concurrency: usize,
 ) {
-    let get_tx_socket = || tx_socket.clone();
-    let get_state = || state.clone();
-
-    rx_message
-        .for_each_concurrent(
-            concurrency,
-            async move |PreparedMessage {
-                            mut message,
-                            mut channel,
-                        }| {
-                // consult cooldown tracker and/or banphrase API
-                let banphrase_future = get_state().banphrase_api.check(message.clone());
-                let response = match get_state().cooldowns.access_raw(&channel) {
-                    Some(read_lock) => {
-                        // let's simply check for cooldown first
-                        match read_lock.cooldown() {
-                            CooldownState::Ready => {
-                                // if this is ready, we don't really care -- we need to check banphrase
-                                // api first.
-                                banphrase_future.await
-                            }
-                            CooldownState::NotReady(how_long) => {
-                                // if this is not ready, we can align banphrase api request and waiting
-                                // time.
-                                futures::future::join(tokio::timer::delay_for(how_long), banphrase_future)
-                                    .await
-                                    .1
-                            }
-                        }
-                    }
-                    None => {
-                        error!("No such channel: {}", channel);
-                        return;
-                    }
-                };
-
-                // now that we've got response from banphrase api, lets check it
-                match response {
-                    Ok(r) => match r.json::<BanphraseResponse>().await {
-                        Ok(r) => {
-                            if r.banned {
-                                info!("Banphrase API says that message is banned -- not sending ({})", message);
-                                return;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Weird response from banphrase API: {:?}", e);
-                            return;
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to consult banphrase API: {:?}", e);
-                        return;
-                    }
-                }
+    let mut lanes: HashMap<Option<String>, Lane> = HashMap::new();
+    // lanes which are closed, but may still be sending what was queued before
+    let mut closing: HashMap<Option<String>, oneshot::Receiver<()>> = HashMap::new();
 
-                // ok, so message is not a banphrase. now we should consult history to find out
-                // whether do we need to modify it
-                // TODO what if modification results in a message becoming banphrase?
-                let mut should_add_to_history = false;
-                match get_state().history.contains(&channel, &message).await {
-                    Some(0) => should_add_to_history = true,
-                    Some(n) => modify_message(&mut message, n - 1),
-                    None => {
-                        error!("No such channel: {}", channel);
-                        return;
-                    }
-                }
+    while let Some(message) = rx_message.next().await {
+        // whispers share account-wide limits, so they share a lane as well
+        let key = if message.whisper {
+            None
+        } else {
+            Some(message.channel.clone())
+        };
+        let lane = lanes.entry(key.clone()).or_insert_with(|| {
+            let (tx_normal, rx_normal) = channel(LANE_CAPACITY);
+            let (tx_priority, rx_priority) = channel(LANE_CAPACITY);
+            let (tx_done, rx_done) = oneshot::channel();
+            // channel may have been joined again, before its previous lane has sent everything
+            let previous = closing.remove(&key);
+            tokio::spawn(lane_loop(
+                rx_normal,
+                rx_priority,
+                previous,
+                tx_done,
+                tx_line.clone(),
+                state.clone(),
+            ));
+            Lane {
+                normal: tx_normal,
+                priority: tx_priority,
+                done: rx_done,
+            }
+        });
 
-                if should_add_to_history {
-                    get_state().history.push(&channel, message.clone()).await;
-                }
+        let queue = if message.priority {
+            &mut lane.priority
+        } else {
+            &mut lane.normal
+        };
+        // channel has to be left even if its lane is full
+        if message.leave {
+            if let Err(err) = queue.send(message).await {
+                error!("Failed to submit PART: {}", err);
+            }
+            // lane stops once it has sent everything queued so far
+            if let Some(lane) = lanes.remove(&key) {
+                closing.retain(|_, done| match done.try_recv() {
+                    Ok(None) => true,
+                    _ => false,
+                });
+                closing.insert(key, lane.done);
+            }
+            continue;
+        }
+        // by the time a full lane gets to this message, it would be long irrelevant
+        if let Err(err) = queue.try_send(message) {
+            let message = err.into_inner();
+            warn!(
+                "Too many messages queued for {} -- not sending ({})",
+                message.channel, message.message
+            );
+        }
+    }
+}
 
-                // bu-u-ut here we need to consult cooldown tracker again to find out whether we can
-                // send this message
-                match get_state().cooldowns.access_raw(&channel) {
-                    Some(read_lock) => {
-                        if let CooldownState::NotReady(how_long) = read_lock.try_reset() {
-                            tokio::timer::delay_for(how_long).await;
-                        }
-
-                        channel.insert(0, '#');
-
-                        let text = irc::MessageBuilder::new("PRIVMSG")
-                            .with_arg(&channel)
-                            .with_trailing(&message)
-                            .string();
-
-                        info!("Sending message: {:?}", text);
-
-                        get_tx_socket()
-                            .lock()
-                            .await
-                            .send(Message::text(text))
-                            .await
-                            .expect("Failed to send message");
-                    }
-                    None => {
-                        error!("No such channel: {}", channel);
-                        return;
-                    }
-                }
-            },
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use async_trait::async_trait;
+
+    use crate::banphrase::{BanphraseError, PajbotAPI};
+
+    /// Takes its time to check messages starting with "slow", banning nothing.
+    struct SlowChecker;
+
+    #[async_trait]
+    impl BanphraseChecker for SlowChecker {
+        async fn is_banned(&self, _: &str, message: &str) -> Result<bool, BanphraseError> {
+            if message.starts_with("slow") {
+                tokio::timer::delay_for(Duration::from_millis(200)).await;
+            }
+            Ok(false)
+        }
+    }
+
+    fn prepared_message(channel: &str, message: &str, priority: bool) -> PreparedMessage {
+        PreparedMessage {
+            channel: channel.to_string(),
+            message: message.to_string(),
+            overflow: Overflow::Truncate,
+            priority,
+            reply_to: None,
+            whisper: false,
+            leave: false,
+            is_retry: false,
+        }
+    }
+
+    /// Runs sender with a given script, returning lines it has sent in order.
+    fn sent_lines<F, Fut>(script: F) -> Vec<String>
+    where
+        F: FnOnce(Sender<PreparedMessage>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let state = Arc::new(MessagingState::new(
+            &vec!["test".to_string(), "other".to_string()],
+            Duration::from_millis(1),
+            Duration::from_secs(30),
+            Box::new(SlowChecker),
+        ));
+        sent_lines_with(state, script)
+    }
+
+    /// Like `sent_lines`, but with a given messaging state.
+    fn sent_lines_with<F, Fut>(state: Arc<MessagingState>, script: F) -> Vec<String>
+    where
+        F: FnOnce(Sender<PreparedMessage>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let runtime = tokio::runtime::Builder::new()
+            .build()
+            .expect("Failed to create runtime");
+
+        let (tx_message, rx_message) = channel(16);
+        let (tx_line, rx_line) = channel(16);
+
+        runtime.block_on(async move {
+            tokio::spawn(sender_event_loop(rx_message, tx_line, state));
+            script(tx_message).await;
+            rx_line.collect().await
+        })
+    }
+
+    #[test]
+    fn test_messages_to_channel_are_sent_in_order() {
+        let lines = sent_lines(async move |mut tx_message| {
+            let messages = [
+                prepared_message("test", "slow first", false),
+                prepared_message("test", "second", false),
+                prepared_message("other", "third", false),
+            ];
+            for message in messages.iter() {
+                tx_message.send(message.clone()).await.unwrap();
+            }
+        });
+
+        assert_eq!(
+            lines,
+            vec![
+                "PRIVMSG #other :third".to_string(),
+                "PRIVMSG #test :slow first".to_string(),
+                "PRIVMSG #test :second".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_priority_messages_go_first() {
+        let lines = sent_lines(async move |mut tx_message| {
+            tx_message
+                .send(prepared_message("test", "slow first", false))
+                .await
+                .unwrap();
+            // let the first one start
+            tokio::timer::delay_for(Duration::from_millis(50)).await;
+            tx_message
+                .send(prepared_message("test", "second", false))
+                .await
+                .unwrap();
+            tx_message.send(prepared_message("test", "urgent", true)).await.unwrap();
+        });
+
+        assert_eq!(
+            lines,
+            vec![
+                "PRIVMSG #test :slow first".to_string(),
+                "PRIVMSG #test :urgent".to_string(),
+                "PRIVMSG #test :second".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replies_are_threaded() {
+        let lines = sent_lines(async move |mut tx_message| {
+            tx_message
+                .send(PreparedMessage {
+                    reply_to: Some("b34ccfc7-4977-403a-8a94-33c6bac34fb8".to_string()),
+                    ..prepared_message("test", "hello", false)
+                })
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(
+            lines,
+            vec!["@reply-parent-msg-id=b34ccfc7-4977-403a-8a94-33c6bac34fb8 PRIVMSG #test :hello".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_queued_messages_are_sent_before_leaving() {
+        let state = Arc::new(MessagingState::new(
+            &vec!["test".to_string()],
+            Duration::from_millis(1),
+            Duration::from_secs(30),
+            Box::new(SlowChecker),
+        ));
+        let lines = sent_lines_with(state.clone(), async move |mut tx_message| {
+            tx_message
+                .send(prepared_message("test", "slow goodbye", false))
+                .await
+                .unwrap();
+            state.leave_channel("test");
+            tx_message
+                .send(PreparedMessage::leave("test".to_string()))
+                .await
+                .unwrap();
+            tx_message
+                .send(prepared_message("test", "too late", false))
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(
+            lines,
+            vec!["PRIVMSG #test :slow goodbye".to_string(), "PART #test".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_lane_stops_after_leaving() {
+        let state = Arc::new(MessagingState::new(
+            &vec!["test".to_string()],
+            Duration::from_millis(1),
+            Duration::from_secs(30),
+            Box::new(SlowChecker),
+        ));
+        let lines = sent_lines_with(state.clone(), async move |mut tx_message| {
+            tx_message.send(prepared_message("test", "hello", false)).await.unwrap();
+            state.leave_channel("test");
+            tx_message
+                .send(PreparedMessage::leave("test".to_string()))
+                .await
+                .unwrap();
+            tokio::timer::delay_for(Duration::from_millis(50)).await;
+
+            // only the sender and the test itself hold the state, and not the lane
+            assert_eq!(Arc::strong_count(&state), 2);
+        });
+
+        assert_eq!(
+            lines,
+            vec!["PRIVMSG #test :hello".to_string(), "PART #test".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_channel_joined_again_is_not_left() {
+        let state = Arc::new(MessagingState::new(
+            &vec!["test".to_string()],
+            Duration::from_millis(1),
+            Duration::from_secs(30),
+            Box::new(SlowChecker),
+        ));
+        let lines = sent_lines_with(state.clone(), async move |mut tx_message| {
+            state.leave_channel("test");
+            state.add_channel("test");
+            tx_message
+                .send(PreparedMessage::leave("test".to_string()))
+                .await
+                .unwrap();
+            tx_message.send(prepared_message("test", "hello", false)).await.unwrap();
+        });
+
+        assert_eq!(lines, vec!["PRIVMSG #test :hello".to_string()]);
+    }
+
+    #[test]
+    fn test_split_parts_are_not_chat_commands() {
+        let lines = sent_lines(async move |mut tx_message| {
+            tx_message
+                .send(PreparedMessage {
+                    overflow: Overflow::Split,
+                    ..prepared_message(
+                        "test",
+                        &format!("{} /ban someone", "a".repeat(MAX_TEXT_LENGTH - 1)),
+                        false,
+                    )
+                })
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "PRIVMSG #test :\u{e0000}/ban someone");
+    }
+
+    #[test]
+    fn test_whispers_are_sent_as_commands() {
+        let lines = sent_lines(async move |mut tx_message| {
+            tx_message
+                .send(PreparedMessage {
+                    whisper: true,
+                    overflow: Overflow::Split,
+                    ..prepared_message("alice", &"word ".repeat(120), false)
+                })
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(lines.len(), 2);
+        for line in lines.iter() {
+            assert!(
+                line.starts_with("PRIVMSG #jtv :/w alice word"),
+                "unexpected line: {}",
+                line
+            );
+            let text = line.splitn(2, " :").nth(1).unwrap();
+            assert!(text.chars().count() <= MAX_MESSAGE_LENGTH, "too long: {}", text);
+        }
+    }
+
+    fn messaging_state(channel: &str) -> MessagingState {
+        MessagingState::new(
+            &vec![channel.to_string()],
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+            Box::new(PajbotAPI::new("http://localhost".to_string())),
         )
-        .await;
+    }
+
+    #[test]
+    fn test_cooldown_follows_role() {
+        let state = messaging_state("test");
+
+        state.update_role("test", ChannelRole::Moderator);
+        state.cooldowns.access(&"test".to_string());
+        std::thread::sleep(PRIVILEGED_COOLDOWN);
+
+        match state.cooldowns.access(&"test".to_string()) {
+            Some(CooldownState::Ready) => assert!(true),
+            _ => assert!(false, "moderator cooldown should have passed"),
+        }
+
+        state.update_role("test", ChannelRole::User);
+        std::thread::sleep(PRIVILEGED_COOLDOWN);
+
+        match state.cooldowns.access(&"test".to_string()) {
+            Some(CooldownState::NotReady(_)) => assert!(true),
+            _ => assert!(false, "default cooldown should be restored"),
+        }
+    }
+
+    #[test]
+    fn test_channel_state_is_torn_down() {
+        let state = messaging_state("test");
+        state.add_channel("other");
+        state.suspend("other", None);
+
+        assert!(state.cooldowns.contains(&"other".to_string()), "channel was not added");
+
+        state.remove_channel("other");
+
+        assert!(
+            !state.cooldowns.contains(&"other".to_string()),
+            "channel was not removed"
+        );
+        assert!(state.suspension("other").is_none(), "suspension should be forgotten");
+        assert!(
+            state.cooldowns.contains(&"test".to_string()),
+            "other channels should not be affected"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_message_is_retried_once() {
+        let state = messaging_state("test");
+        state.last_sent.insert(
+            "test".to_string(),
+            PreparedMessage {
+                channel: "test".to_string(),
+                message: "message".to_string(),
+                overflow: Overflow::Truncate,
+                priority: false,
+                reply_to: None,
+                whisper: false,
+                leave: false,
+                is_retry: false,
+            },
+        );
+
+        match state.handle_notice("test", "msg_duplicate", "") {
+            Some(message) => {
+                assert_eq!(message.message, "message");
+                assert!(message.is_retry, "retried message should be marked as such");
+                state.last_sent.insert("test".to_string(), message);
+            }
+            None => assert!(false, "message should be retried"),
+        }
+
+        assert!(
+            state.handle_notice("test", "msg_duplicate", "").is_none(),
+            "message should not be retried twice"
+        );
+    }
+
+    #[test]
+    fn test_timeout_suspends_channel() {
+        let state = messaging_state("test");
+
+        state.handle_notice("test", "msg_timedout", "You are timed out for 600 more seconds.");
+
+        match state.suspension("test") {
+            Some(Suspension::For(duration)) => assert!(
+                duration > Duration::from_secs(590) && duration <= Duration::from_secs(600),
+                "wrong suspension duration: {:?}",
+                duration
+            ),
+            _ => assert!(false, "channel should be suspended"),
+        }
+    }
+
+    #[test]
+    fn test_ban_suspends_channel_indefinitely() {
+        let state = messaging_state("test");
+
+        state.handle_notice("test", "msg_banned", "You are permanently banned from talking in test.");
+
+        match state.suspension("test") {
+            Some(Suspension::Indefinite) => assert!(true),
+            _ => assert!(false, "channel should be suspended indefinitely"),
+        }
+        assert!(
+            state.suspension("other").is_none(),
+            "other channels should not be affected"
+        );
+    }
 }
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Copy)]
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum PermissionLevel {
     Admin = 100,
     User = 10,
@@ -3,7 +3,8 @@ pub use std::time::Duration;
 pub use async_trait::async_trait;
 pub use log::*;
 
-pub use crate::executor::{CommandCooldown, ExecutableCommand, ExecutionOutcome, ShareableExecutableCommand};
+pub use crate::executor::{CommandCooldown, ExecutableCommand, ExecutionOutcome, Overflow, ShareableExecutableCommand};
 pub use crate::irc;
 pub use crate::permissions::{PermissionLevel, PermissionList};
-pub use crate::state::{BotState, Commands};
+pub use crate::state::{valid_channel_name, BotState, ChannelRequestError, Commands};
+pub use crate::twitch;
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cooldown::CooldownState;
+
+/// Token bucket which mirrors the way Twitch counts messages.
+///
+/// Every token taken from the bucket returns to it exactly `period` later, so no more
+/// than `limit` tokens can ever be taken within any window of length `period`.
+///
+/// Limit can be specified per call, which allows to enforce several limits sharing the same
+/// counter (e.g. 20 messages per 30 seconds in general, but 100 in channels where we are moderator).
+pub struct TokenBucket {
+    period: Duration,
+    taken: Mutex<VecDeque<Instant>>,
+}
+
+impl TokenBucket {
+    pub fn new(period: Duration) -> TokenBucket {
+        TokenBucket {
+            period,
+            taken: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Tries to take a token, given that no more than `limit` tokens can be taken within period.
+    ///
+    /// If there are no tokens left, CooldownState::NotReady is returned with the time until
+    /// the next token becomes available.
+    ///
+    /// Panics if `limit` is zero, since no token could ever be taken.
+    pub fn try_acquire(&self, limit: usize) -> CooldownState {
+        assert!(limit > 0, "token bucket limit must be positive");
+
+        let now = Instant::now();
+        let mut taken = self
+            .taken
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened");
+
+        while let Some(timestamp) = taken.front() {
+            if *timestamp + self.period <= now {
+                taken.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if taken.len() < limit {
+            taken.push_back(now);
+            CooldownState::Ready
+        } else {
+            // we need (len - limit + 1) oldest tokens to return to get below the limit
+            let returns_at = taken[taken.len() - limit] + self.period;
+            CooldownState::NotReady(returns_at - now)
+        }
+    }
+
+    /// Takes a token, waiting for it to become available if needed.
+    ///
+    /// Panics if `limit` is zero, see `try_acquire`.
+    pub async fn acquire(&self, limit: usize) {
+        while let CooldownState::NotReady(how_long) = self.try_acquire(limit) {
+            tokio::timer::delay_for(how_long).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_limited() {
+        let bucket = TokenBucket::new(Duration::from_millis(50));
+
+        for _ in 0..3 {
+            match bucket.try_acquire(3) {
+                CooldownState::Ready => assert!(true),
+                CooldownState::NotReady(_) => assert!(false, "bucket should have tokens left"),
+            }
+        }
+
+        match bucket.try_acquire(3) {
+            CooldownState::Ready => assert!(false, "bucket should be empty"),
+            CooldownState::NotReady(duration) => {
+                assert!(
+                    duration <= Duration::from_millis(50),
+                    "wrong waiting time: {:?}",
+                    duration
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokens_return_after_period() {
+        let bucket = TokenBucket::new(Duration::from_millis(10));
+
+        match bucket.try_acquire(1) {
+            CooldownState::Ready => assert!(true),
+            CooldownState::NotReady(_) => assert!(false, "bucket should have tokens left"),
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+
+        match bucket.try_acquire(1) {
+            CooldownState::Ready => assert!(true),
+            CooldownState::NotReady(_) => assert!(false, "token should have returned"),
+        }
+    }
+
+    #[test]
+    fn test_limits_share_tokens() {
+        let bucket = TokenBucket::new(Duration::from_secs(30));
+
+        for _ in 0..2 {
+            bucket.try_acquire(5);
+        }
+
+        match bucket.try_acquire(2) {
+            CooldownState::Ready => assert!(false, "lower limit is already exhausted"),
+            CooldownState::NotReady(_) => assert!(true),
+        }
+
+        match bucket.try_acquire(5) {
+            CooldownState::Ready => assert!(true),
+            CooldownState::NotReady(_) => assert!(false, "higher limit is not exhausted yet"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "limit must be positive")]
+    fn test_zero_limit_is_rejected() {
+        let bucket = TokenBucket::new(Duration::from_secs(30));
+
+        bucket.try_acquire(0);
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Tunable parameters of the bot.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Channels to join upon startup.
+    pub channels: Vec<String>,
+    /// Command prefix used in channels which don't have their own.
+    pub prefix: String,
+    /// Per-channel command prefixes.
+    pub channel_prefixes: HashMap<String, String>,
+    /// Cooldown between messages sent to a channel where we have no special role.
+    pub channel_cooldown: Duration,
+    /// How long sent messages are remembered to avoid sending duplicates.
+    pub history_ttl: Duration,
+    /// URL of pajbot banphrase API.
+    pub banphrase_api_url: String,
+    /// How many commands are executed concurrently. Messages are sent one at a time per channel.
+    pub concurrency: usize,
+    /// How long to wait for queued commands and messages to be processed on shutdown.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            channels: Vec::new(),
+            prefix: ">>".to_string(),
+            channel_prefixes: HashMap::new(),
+            channel_cooldown: Duration::from_secs(1),
+            history_ttl: Duration::from_secs(30),
+            banphrase_api_url: "https://pajlada.pajbot.com/api/v1/banphrases/test".to_string(),
+            concurrency: 64,
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+
+/// Handle which can be used to stop a running bot.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    trigger: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the bot to stop. Calling this more than once has no effect.
+    pub fn shutdown(&self) {
+        let trigger = self
+            .trigger
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .take();
+        if let Some(trigger) = trigger {
+            let _ = trigger.send(());
+        }
+    }
+}
+
+/// Signal received by bot components when shutdown is requested.
+#[derive(Clone)]
+pub(crate) struct ShutdownSignal {
+    triggered: Shared<oneshot::Receiver<()>>,
+    timeout: Duration,
+}
+
+impl ShutdownSignal {
+    /// How long components can take to finish their work once shutdown is requested.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Waits until shutdown is requested.
+    ///
+    /// If every handle is dropped without requesting shutdown, this never completes.
+    pub async fn wait(&self) {
+        if self.triggered.clone().await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+pub(crate) fn shutdown_channel(timeout: Duration) -> (ShutdownHandle, ShutdownSignal) {
+    let (tx, rx) = oneshot::channel();
+    (
+        ShutdownHandle {
+            trigger: Arc::new(Mutex::new(Some(tx))),
+        },
+        ShutdownSignal {
+            triggered: rx.shared(),
+            timeout,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_signal_is_received_by_every_listener() {
+        let (handle, signal) = shutdown_channel(Duration::from_secs(1));
+        let other = signal.clone();
+
+        handle.shutdown();
+        handle.shutdown();
+
+        futures::executor::block_on(async move {
+            signal.wait().await;
+            other.wait().await;
+        });
+    }
+
+    #[test]
+    fn test_dropped_handle_does_not_trigger_shutdown() {
+        let (handle, signal) = shutdown_channel(Duration::from_secs(1));
+        drop(handle);
+
+        let wait = signal.wait();
+        futures::pin_mut!(wait);
+
+        match futures::executor::block_on(futures::future::select(wait, futures::future::ready(()))) {
+            futures::future::Either::Left(_) => assert!(false, "shutdown was not requested"),
+            futures::future::Either::Right(_) => assert!(true),
+        }
+    }
+}
@@ -1,44 +1,138 @@
 use async_std::sync::RwLock;
 use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use futures::channel::mpsc::{SendError, Sender};
+use futures::SinkExt;
 
 use crate::executor::ShareableExecutableCommand;
+use crate::history::ChatLog;
 use crate::irc;
 use crate::permissions::PermissionList;
+use crate::settings::Settings;
+use crate::storage::Storage;
 
 pub type Commands<T> = HashMap<String, ShareableExecutableCommand<T>>;
 
+/// How many recent chat messages are remembered in each channel.
+const CHAT_LOG_CAPACITY: usize = 200;
+
+/// Twitch logins, which channel names are, are never longer than this.
+const MAX_CHANNEL_NAME_LENGTH: usize = 25;
+
+/// Request to change the set of channels the bot is in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelRequest {
+    Join(String),
+    Part(String),
+}
+
+/// Reason why a request to join or leave a channel was not accepted.
+#[derive(Debug)]
+pub enum ChannelRequestError {
+    InvalidName(String),
+    Closed(SendError),
+}
+
+impl Display for ChannelRequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ChannelRequestError::InvalidName(name) => f.write_fmt(format_args!("invalid channel name: {:?}", name)),
+            ChannelRequestError::Closed(err) => f.write_fmt(format_args!("failed to submit request: {}", err)),
+        }
+    }
+}
+
+impl Error for ChannelRequestError {}
+
+impl From<SendError> for ChannelRequestError {
+    fn from(err: SendError) -> Self {
+        ChannelRequestError::Closed(err)
+    }
+}
+
 pub struct BotState<T: 'static + Send + Sync> {
     pub username: String,
     pub prefix: String,
-    pub channels: BTreeSet<String>,
+    pub channel_prefixes: HashMap<String, String>,
+    pub channels: RwLock<BTreeSet<String>>,
     pub commands: Commands<T>,
     pub permissions: PermissionList,
     pub data: RwLock<T>,
+    pub storage: Arc<dyn Storage>,
+    /// Recent messages users sent to channels, not counting commands.
+    pub chat: ChatLog,
+    channel_requests: Sender<ChannelRequest>,
 }
 
 impl<T: 'static + Send + Sync> BotState<T> {
     pub fn new(
         username: String,
-        prefix: String,
-        channels: Vec<String>,
+        settings: &Settings,
         commands: Commands<T>,
         permissions: PermissionList,
         data: T,
+        storage: Arc<dyn Storage>,
+        channel_requests: Sender<ChannelRequest>,
     ) -> BotState<T> {
         BotState {
             username,
-            prefix,
-            channels: channels.into_iter().map(|s| s.to_string()).collect(),
+            prefix: settings.prefix.clone(),
+            channel_prefixes: settings
+                .channel_prefixes
+                .iter()
+                .map(|(channel, prefix)| (normalize_channel_name(channel), prefix.clone()))
+                .collect(),
+            channels: RwLock::new(settings.channels.iter().map(|s| normalize_channel_name(s)).collect()),
             commands,
             permissions,
             data: RwLock::new(data),
+            storage,
+            chat: ChatLog::new(CHAT_LOG_CAPACITY),
+            channel_requests,
         }
     }
 
+    /// Requests the bot to join a channel.
+    ///
+    /// Channel is joined asynchronously, as soon as the rate limits allow it.
+    pub async fn join_channel(&self, channel: &str) -> Result<(), ChannelRequestError> {
+        let channel = valid_channel_name(channel)?;
+        self.channel_requests
+            .clone()
+            .send(ChannelRequest::Join(channel))
+            .await?;
+        Ok(())
+    }
+
+    /// Requests the bot to leave a channel.
+    ///
+    /// Commands which leave a channel and reply to it should use `ExecutionOutcome::then_part` instead, which
+    /// makes sure the reply is sent before the channel is left.
+    pub async fn part_channel(&self, channel: &str) -> Result<(), ChannelRequestError> {
+        let channel = valid_channel_name(channel)?;
+        self.channel_requests
+            .clone()
+            .send(ChannelRequest::Part(channel))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns command prefix used in a given channel.
+    pub fn prefix_for(&self, channel: &str) -> &str {
+        self.channel_prefixes.get(channel).unwrap_or(&self.prefix)
+    }
+
     pub fn try_convert_to_command(&self, message: &irc::Message) -> Option<String> {
+        let prefix = match message.channel() {
+            Some(channel) => self.prefix_for(channel),
+            None => &self.prefix,
+        };
         if let Some(s) = message.trailing {
-            if s.starts_with(&self.prefix) {
-                return Some((&s[self.prefix.len()..]).trim_start().to_string());
+            if s.starts_with(prefix) {
+                return Some((&s[prefix.len()..]).trim_start().to_string());
             }
             if s.starts_with(&self.username) {
                 return Some((&s[self.username.len()..]).trim_start().to_string());
@@ -47,3 +141,107 @@ impl<T: 'static + Send + Sync> BotState<T> {
         None
     }
 }
+
+/// Converts channel name to the form used by Twitch: lowercase, without leading '#'.
+pub fn normalize_channel_name(channel: &str) -> String {
+    channel.trim().trim_start_matches('#').to_lowercase()
+}
+
+/// Normalizes channel name, making sure it is a single valid Twitch login which can be sent in JOIN or PART.
+pub fn valid_channel_name(channel: &str) -> Result<String, ChannelRequestError> {
+    let normalized = normalize_channel_name(channel);
+    let is_valid = !normalized.is_empty()
+        && normalized.len() <= MAX_CHANNEL_NAME_LENGTH
+        && normalized.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_valid {
+        Ok(normalized)
+    } else {
+        Err(ChannelRequestError::InvalidName(channel.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::storage::MemoryStorage;
+
+    fn bot_state(settings: &Settings) -> BotState<()> {
+        let (tx, _) = futures::channel::mpsc::channel(1);
+        BotState::new(
+            "bot".to_string(),
+            settings,
+            HashMap::new(),
+            PermissionList::new(HashMap::new()),
+            (),
+            Arc::new(MemoryStorage::new()),
+            tx,
+        )
+    }
+
+    #[test]
+    fn test_prefix_can_be_overridden_per_channel() {
+        let settings = Settings {
+            prefix: ">>".to_string(),
+            channel_prefixes: vec![("#Other".to_string(), "!".to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+        let state = bot_state(&settings);
+
+        let in_test = irc::Message::parse("PRIVMSG #test :>> ping").unwrap();
+        let in_other = irc::Message::parse("PRIVMSG #other :!ping").unwrap();
+        let wrong_prefix = irc::Message::parse("PRIVMSG #other :>> ping").unwrap();
+
+        assert_eq!(state.try_convert_to_command(&in_test), Some("ping".to_string()));
+        assert_eq!(state.try_convert_to_command(&in_other), Some("ping".to_string()));
+        assert_eq!(state.try_convert_to_command(&wrong_prefix), None);
+    }
+
+    #[test]
+    fn test_whispers_use_default_prefix() {
+        let settings = Settings {
+            prefix: ">>".to_string(),
+            channel_prefixes: vec![("bot".to_string(), "!".to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+        let state = bot_state(&settings);
+
+        let whisper = irc::Message::parse(":alice!alice@alice.tmi.twitch.tv WHISPER bot :>> ping").unwrap();
+        let channel_prefix = irc::Message::parse(":alice!alice@alice.tmi.twitch.tv WHISPER bot :!ping").unwrap();
+
+        assert_eq!(state.try_convert_to_command(&whisper), Some("ping".to_string()));
+        assert_eq!(state.try_convert_to_command(&channel_prefix), None);
+    }
+
+    #[test]
+    fn test_channel_name_is_normalized() {
+        assert_eq!(normalize_channel_name("#Forsen"), "forsen");
+        assert_eq!(normalize_channel_name(" pajlada "), "pajlada");
+    }
+
+    #[test]
+    fn test_invalid_channel_names_are_rejected() {
+        assert_eq!(valid_channel_name("#Forsen").ok(), Some("forsen".to_string()));
+        assert_eq!(
+            valid_channel_name("some_user_42").ok(),
+            Some("some_user_42".to_string())
+        );
+
+        for name in &[
+            "",
+            "#",
+            "a,b",
+            "#a,#b",
+            "two words",
+            "a\r\nPRIVMSG #b :hi",
+            "ünïcode",
+            "a".repeat(26).as_str(),
+        ] {
+            match valid_channel_name(name) {
+                Err(ChannelRequestError::InvalidName(_)) => assert!(true),
+                other => assert!(false, "{:?} should be rejected, got {:?}", name, other),
+            }
+        }
+    }
+}
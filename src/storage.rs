@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// Key-value storage for the data bot keeps between restarts.
+pub trait Storage: Send + Sync {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    /// Makes sure that everything saved so far is persisted.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Storage which keeps everything in memory, i.e. doesn't persist anything.
+#[derive(Default)]
+pub struct MemoryStorage {
+    values: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        Default::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .values
+            .read()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .get(key)
+            .cloned())
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.values
+            .write()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+/// Storage which keeps every value in a separate file in a given directory.
+pub struct FileStorage {
+    directory: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(directory: PathBuf) -> io::Result<FileStorage> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(FileStorage { directory })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let is_valid = !key.is_empty()
+            && !key.starts_with('.')
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+        if is_valid {
+            Ok(self.directory.join(key))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage key: {:?}", key),
+            ))
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(key)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        // write to a temporary file first, so that value is never left half-written
+        let path = self.path(key)?;
+        let temporary = self.directory.join(format!(".{}.tmp", key));
+        std::fs::write(&temporary, value)?;
+        std::fs::rename(&temporary, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn check_round_trip(storage: &dyn Storage) {
+        match storage.load("missing") {
+            Ok(None) => assert!(true),
+            _ => assert!(false, "nothing was saved under this key"),
+        }
+
+        storage.save("key", b"value").expect("failed to save");
+        storage.save("key", b"new value").expect("failed to overwrite");
+
+        match storage.load("key") {
+            Ok(Some(value)) => assert_eq!(value, b"new value"),
+            _ => assert!(false, "saved value is lost"),
+        }
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_round_trip(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let directory = std::env::temp_dir().join(format!("bot-storage-test-{}", std::process::id()));
+        let storage = FileStorage::new(directory.clone()).expect("failed to create storage");
+
+        check_round_trip(&storage);
+
+        match storage.save("../escape", b"value") {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Ok(()) => assert!(false, "key should not be able to escape storage directory"),
+        }
+
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
@@ -0,0 +1,285 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use async_std::net::TcpStream;
+
+use async_trait::async_trait;
+use async_tungstenite::connect_async;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::io::{AsyncRead, AsyncWrite, BufReader};
+use futures::sink::Sink;
+use futures::stream::{self, Stream};
+use futures::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, SinkExt, StreamExt};
+
+use log::*;
+use tungstenite::Message;
+use url::Url;
+
+use crate::connection::ConnectionError;
+
+/// Outgoing IRC lines, without trailing "\r\n".
+pub type LineSink = Pin<Box<dyn Sink<String, Error = ConnectionError> + Send>>;
+
+/// Incoming IRC lines, without trailing "\r\n". Connection is considered lost once this stream ends.
+pub type LineStream = Pin<Box<dyn Stream<Item = Result<String, ConnectionError>> + Send>>;
+
+/// A way to connect to IRC server.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Opens a new connection.
+    async fn connect(&self) -> Result<(LineSink, LineStream), ConnectionError>;
+}
+
+/// IRC over WebSocket, as used by Twitch web chat.
+pub struct WebSocketTransport {
+    url: Url,
+}
+
+impl WebSocketTransport {
+    pub fn new(url: Url) -> WebSocketTransport {
+        WebSocketTransport { url }
+    }
+}
+
+impl Default for WebSocketTransport {
+    fn default() -> WebSocketTransport {
+        WebSocketTransport::new(Url::parse("wss://irc-ws.chat.twitch.tv:443").unwrap())
+    }
+}
+
+/// Splits a single websocket message into IRC lines.
+fn to_lines(message: Result<Message, tungstenite::Error>) -> Vec<Result<String, ConnectionError>> {
+    match message {
+        Ok(Message::Text(text)) => text.split_terminator("\r\n").map(|s| Ok(s.to_string())).collect(),
+        Ok(Message::Close(frame)) => {
+            info!("Server closed connection: {:?}", frame);
+            vec![Err(ConnectionError::Closed)]
+        }
+        Ok(message) => {
+            trace!("Ignoring non-text message: {:?}", message);
+            Vec::new()
+        }
+        Err(err) => vec![Err(err.into())],
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn connect(&self) -> Result<(LineSink, LineStream), ConnectionError> {
+        info!("Connecting to {}...", self.url);
+        let (ws_stream, _) = connect_async(self.url.clone()).await?;
+        let (tx_socket, rx_socket) = ws_stream.split();
+
+        let sink = tx_socket
+            .with(|line: String| futures::future::ok::<_, tungstenite::Error>(Message::text(line)))
+            .sink_map_err(ConnectionError::from);
+        let stream = rx_socket.map(|message| stream::iter(to_lines(message))).flatten();
+
+        Ok((Box::pin(sink), Box::pin(stream)))
+    }
+}
+
+/// Plain IRC over TCP, optionally secured with TLS.
+pub struct TcpTransport {
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+impl TcpTransport {
+    /// Unencrypted connection. Twitch listens on port 6667.
+    pub fn plain(host: impl Into<String>, port: u16) -> TcpTransport {
+        TcpTransport {
+            host: host.into(),
+            port,
+            tls: false,
+        }
+    }
+
+    /// TLS connection. Twitch listens on port 6697.
+    pub fn tls(host: impl Into<String>, port: u16) -> TcpTransport {
+        TcpTransport {
+            host: host.into(),
+            port,
+            tls: true,
+        }
+    }
+
+    /// Creates transport from `irc://host[:port]` or `ircs://host[:port]` URL.
+    pub fn from_url(url: &Url) -> Option<TcpTransport> {
+        let host = url.host_str()?;
+        match url.scheme() {
+            "irc" => Some(TcpTransport::plain(host, url.port().unwrap_or(6667))),
+            "ircs" => Some(TcpTransport::tls(host, url.port().unwrap_or(6697))),
+            _ => None,
+        }
+    }
+}
+
+/// Wraps a byte stream into IRC line sink and stream.
+fn into_lines<S>(socket: S) -> (LineSink, LineStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = socket.split();
+
+    let sink = writer
+        .into_sink()
+        .with(|line: String| futures::future::ok::<_, io::Error>(format!("{}\r\n", line)))
+        .sink_map_err(ConnectionError::from);
+    let stream = BufReader::new(reader)
+        .lines()
+        .map(|line| line.map_err(ConnectionError::from));
+
+    (Box::pin(sink), Box::pin(stream))
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn connect(&self) -> Result<(LineSink, LineStream), ConnectionError> {
+        info!("Connecting to {}:{} (TLS: {})...", self.host, self.port, self.tls);
+        let socket = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        if self.tls {
+            let connector = native_tls::TlsConnector::new().map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            let socket = async_native_tls::TlsConnector::from(connector)
+                .connect(&self.host, socket)
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            Ok(into_lines(socket))
+        } else {
+            Ok(into_lines(socket))
+        }
+    }
+}
+
+/// In-memory transport, which allows to run the bot against a fake server.
+///
+/// Every connection opened through this transport is handed to the paired `MemoryServer`.
+pub struct MemoryTransport {
+    tx_connection: Mutex<UnboundedSender<MemoryConnection>>,
+}
+
+/// Server side of `MemoryTransport`.
+pub struct MemoryServer {
+    rx_connection: UnboundedReceiver<MemoryConnection>,
+}
+
+/// Server side of a single in-memory connection. Client is disconnected when this is dropped.
+pub struct MemoryConnection {
+    tx: UnboundedSender<String>,
+    rx: UnboundedReceiver<String>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryServer) {
+        let (tx_connection, rx_connection) = unbounded();
+        (
+            MemoryTransport {
+                tx_connection: Mutex::new(tx_connection),
+            },
+            MemoryServer { rx_connection },
+        )
+    }
+}
+
+impl MemoryServer {
+    /// Waits for the client to connect. Returns `None` if the transport is dropped.
+    pub async fn accept(&mut self) -> Option<MemoryConnection> {
+        self.rx_connection.next().await
+    }
+}
+
+impl MemoryConnection {
+    /// Sends a line to the client. Returns `false` if the client has disconnected.
+    pub fn send(&self, line: impl Into<String>) -> bool {
+        self.tx.unbounded_send(line.into()).is_ok()
+    }
+
+    /// Receives a line from the client. Returns `None` if the client has disconnected.
+    pub async fn recv(&mut self) -> Option<String> {
+        self.rx.next().await
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn connect(&self) -> Result<(LineSink, LineStream), ConnectionError> {
+        let (tx_client, rx_server) = unbounded();
+        let (tx_server, rx_client) = unbounded();
+
+        let connection = MemoryConnection {
+            tx: tx_server,
+            rx: rx_server,
+        };
+        self.tx_connection
+            .lock()
+            .expect("lock is poisoned, but this shouldn't have happened")
+            .unbounded_send(connection)
+            .map_err(|_| ConnectionError::Closed)?;
+
+        let sink = tx_client.sink_map_err(|_| ConnectionError::Closed);
+        let stream = rx_client.map(Ok);
+
+        Ok((Box::pin(sink), Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_memory_transport_passes_lines() {
+        futures::executor::block_on(async {
+            let (transport, mut server) = MemoryTransport::pair();
+
+            let (mut tx, mut rx) = transport.connect().await.expect("failed to connect");
+            let mut connection = server.accept().await.expect("connection is lost");
+
+            tx.send("PING".to_string()).await.expect("failed to send");
+            assert_eq!(connection.recv().await, Some("PING".to_string()));
+
+            assert!(connection.send("PONG"), "client has disconnected");
+            match rx.next().await {
+                Some(Ok(line)) => assert_eq!(line, "PONG"),
+                _ => assert!(false, "line is lost"),
+            }
+
+            drop(connection);
+            match rx.next().await {
+                None => assert!(true),
+                _ => assert!(false, "connection should be closed"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_lines_are_split() {
+        futures::executor::block_on(async {
+            let socket = futures::io::Cursor::new(b"PING :a\r\nPING :b\r\n".to_vec());
+            let (_, rx) = into_lines(socket);
+
+            let lines: Vec<String> = rx.map(|line| line.expect("failed to read")).collect().await;
+            assert_eq!(lines, vec!["PING :a".to_string(), "PING :b".to_string()]);
+        });
+    }
+
+    #[test]
+    fn test_transport_is_chosen_by_url() {
+        let url = Url::parse("ircs://irc.chat.twitch.tv").unwrap();
+        match TcpTransport::from_url(&url) {
+            Some(transport) => {
+                assert!(transport.tls, "ircs should use TLS");
+                assert_eq!(transport.port, 6697);
+            }
+            None => assert!(false, "ircs URL should be supported"),
+        }
+
+        let url = Url::parse("wss://irc-ws.chat.twitch.tv").unwrap();
+        assert!(TcpTransport::from_url(&url).is_none(), "websocket URL is not plain IRC");
+    }
+}
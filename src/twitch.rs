@@ -0,0 +1,643 @@
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::irc;
+
+/// A chat badge, such as `moderator/1` or `subscriber/12`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+/// An emote used in a message, with the character ranges (inclusive) it occupies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Emote {
+    pub id: String,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// Information about a user which Twitch attaches to most of the messages.
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub id: Option<String>,
+    pub login: String,
+    pub display_name: String,
+    pub badges: Vec<Badge>,
+    pub color: Option<String>,
+}
+
+impl User {
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|badge| badge.name == name)
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        self.has_badge("moderator") || self.has_badge("broadcaster")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Privmsg {
+    pub channel: String,
+    pub room_id: Option<String>,
+    pub id: Option<String>,
+    pub user: User,
+    pub text: String,
+    pub emotes: Vec<Emote>,
+    pub bits: Option<u64>,
+    pub timestamp: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Whisper {
+    pub recipient: String,
+    pub id: Option<String>,
+    pub thread_id: Option<String>,
+    pub user: User,
+    pub text: String,
+    pub emotes: Vec<Emote>,
+}
+
+#[derive(Debug, Clone)]
+pub enum UserNoticeKind {
+    Sub {
+        plan: Option<String>,
+        cumulative_months: Option<u64>,
+    },
+    Resub {
+        plan: Option<String>,
+        cumulative_months: Option<u64>,
+        streak_months: Option<u64>,
+    },
+    SubGift {
+        plan: Option<String>,
+        recipient_login: Option<String>,
+        recipient_display_name: Option<String>,
+        months: Option<u64>,
+    },
+    Raid {
+        login: Option<String>,
+        display_name: Option<String>,
+        viewer_count: Option<u64>,
+    },
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct UserNotice {
+    pub channel: String,
+    pub room_id: Option<String>,
+    pub id: Option<String>,
+    pub user: User,
+    pub kind: UserNoticeKind,
+    pub system_message: Option<String>,
+    pub text: Option<String>,
+    pub emotes: Vec<Emote>,
+    pub timestamp: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClearChat {
+    pub channel: String,
+    /// User whose messages were cleared, or `None` if the whole chat was cleared.
+    pub target: Option<String>,
+    /// Duration of the timeout, or `None` if the user was permanently banned.
+    pub ban_duration: Option<Duration>,
+    pub timestamp: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClearMsg {
+    pub channel: String,
+    pub login: Option<String>,
+    pub target_message_id: Option<String>,
+    pub text: Option<String>,
+    pub timestamp: Option<SystemTime>,
+}
+
+/// Room settings. Only settings that have changed are present.
+#[derive(Debug, Clone)]
+pub struct RoomState {
+    pub channel: String,
+    pub room_id: Option<String>,
+    pub emote_only: Option<bool>,
+    /// Minutes a user has to follow to be able to chat; `-1` means followers-only mode is off.
+    pub followers_only: Option<i64>,
+    pub r9k: Option<bool>,
+    /// Seconds a user has to wait between messages.
+    pub slow: Option<u64>,
+    pub subs_only: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserState {
+    pub channel: String,
+    pub user: User,
+    pub emote_sets: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GlobalUserState {
+    pub user: User,
+    pub emote_sets: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HostTarget {
+    pub channel: String,
+    /// Channel being hosted, or `None` if hosting has stopped.
+    pub target: Option<String>,
+    pub viewers: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub channel: Option<String>,
+    pub msg_id: Option<String>,
+    pub text: String,
+}
+
+/// Typed representation of a message received from Twitch IRC.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Privmsg(Privmsg),
+    Whisper(Whisper),
+    UserNotice(UserNotice),
+    ClearChat(ClearChat),
+    ClearMsg(ClearMsg),
+    RoomState(RoomState),
+    UserState(UserState),
+    GlobalUserState(GlobalUserState),
+    HostTarget(HostTarget),
+    Notice(Notice),
+    Join {
+        channel: String,
+        login: String,
+    },
+    Part {
+        channel: String,
+        login: String,
+    },
+    Ping(Option<String>),
+    Pong,
+    Reconnect,
+    /// Any message this module doesn't know about (e.g. numeric replies or CAP).
+    Other(String),
+}
+
+fn tag(message: &irc::Message, key: &str) -> Option<String> {
+    message.tag_value(key).filter(|v| !v.is_empty()).map(|v| v.into_owned())
+}
+
+fn tag_number(message: &irc::Message, key: &str) -> Option<u64> {
+    message.tag_value(key).and_then(|v| v.parse().ok())
+}
+
+fn tag_flag(message: &irc::Message, key: &str) -> Option<bool> {
+    message.tag_value(key).map(|v| v != "0")
+}
+
+fn tag_timestamp(message: &irc::Message, key: &str) -> Option<SystemTime> {
+    tag_number(message, key).map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+fn channel(message: &irc::Message) -> Result<String, Box<dyn Error>> {
+    Ok(message
+        .first_arg_as_channel_name()
+        .ok_or("Channel expected")?
+        .to_string())
+}
+
+fn prefix_login(message: &irc::Message) -> Option<String> {
+    match message.prefix {
+        irc::Prefix::Full { nick, .. } => Some(nick.to_string()),
+        irc::Prefix::UserHost { user, .. } => Some(user.to_string()),
+        _ => None,
+    }
+}
+
+/// Parses badges of form `name/version,name/version`.
+pub fn parse_badges(raw: &str) -> Vec<Badge> {
+    raw.split_terminator(',')
+        .filter(|badge| !badge.is_empty())
+        .map(|badge| {
+            let mut iter = badge.splitn(2, '/');
+            Badge {
+                name: iter.next().unwrap().to_string(),
+                version: iter.next().unwrap_or("").to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Parses emotes of form `id:from-to,from-to/id:from-to`.
+pub fn parse_emotes(raw: &str) -> Vec<Emote> {
+    raw.split_terminator('/')
+        .filter_map(|emote| {
+            let mut iter = emote.splitn(2, ':');
+            let id = iter.next()?;
+            let ranges = iter
+                .next()?
+                .split_terminator(',')
+                .filter_map(|range| {
+                    let mut iter = range.splitn(2, '-');
+                    Some((iter.next()?.parse().ok()?, iter.next()?.parse().ok()?))
+                })
+                .collect();
+            Some(Emote {
+                id: id.to_string(),
+                ranges,
+            })
+        })
+        .collect()
+}
+
+fn user(message: &irc::Message, login: Option<String>) -> User {
+    let login = login.unwrap_or_default();
+    User {
+        id: tag(message, "user-id"),
+        display_name: tag(message, "display-name").unwrap_or_else(|| login.clone()),
+        login,
+        badges: parse_badges(&message.tag_value("badges").unwrap_or_default()),
+        color: tag(message, "color"),
+    }
+}
+
+fn emotes(message: &irc::Message) -> Vec<Emote> {
+    parse_emotes(&message.tag_value("emotes").unwrap_or_default())
+}
+
+fn emote_sets(message: &irc::Message) -> Vec<String> {
+    message
+        .tag_value("emote-sets")
+        .unwrap_or_default()
+        .split_terminator(',')
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn user_notice_kind(message: &irc::Message) -> UserNoticeKind {
+    let plan = tag(message, "msg-param-sub-plan");
+    match message.tag_value("msg-id").unwrap_or_default().as_ref() {
+        "sub" => UserNoticeKind::Sub {
+            plan,
+            cumulative_months: tag_number(message, "msg-param-cumulative-months"),
+        },
+        "resub" => UserNoticeKind::Resub {
+            plan,
+            cumulative_months: tag_number(message, "msg-param-cumulative-months"),
+            streak_months: tag_number(message, "msg-param-streak-months"),
+        },
+        "subgift" | "anonsubgift" => UserNoticeKind::SubGift {
+            plan,
+            recipient_login: tag(message, "msg-param-recipient-user-name"),
+            recipient_display_name: tag(message, "msg-param-recipient-display-name"),
+            months: tag_number(message, "msg-param-months"),
+        },
+        "raid" => UserNoticeKind::Raid {
+            login: tag(message, "msg-param-login"),
+            display_name: tag(message, "msg-param-displayName"),
+            viewer_count: tag_number(message, "msg-param-viewerCount"),
+        },
+        other => UserNoticeKind::Other(other.to_string()),
+    }
+}
+
+impl Event {
+    /// Converts IRC message into Twitch event.
+    pub fn from_message(message: &irc::Message) -> Result<Event, Box<dyn Error>> {
+        Ok(match message.command.name {
+            "PRIVMSG" => Event::Privmsg(Privmsg {
+                channel: channel(message)?,
+                room_id: tag(message, "room-id"),
+                id: tag(message, "id"),
+                user: user(message, prefix_login(message)),
+                text: message.trailing.ok_or("Message text expected")?.to_string(),
+                emotes: emotes(message),
+                bits: tag_number(message, "bits"),
+                timestamp: tag_timestamp(message, "tmi-sent-ts"),
+            }),
+            "WHISPER" => Event::Whisper(Whisper {
+                recipient: message.command.args.first().ok_or("Recipient expected")?.to_string(),
+                id: tag(message, "message-id"),
+                thread_id: tag(message, "thread-id"),
+                user: user(message, prefix_login(message)),
+                text: message.trailing.ok_or("Message text expected")?.to_string(),
+                emotes: emotes(message),
+            }),
+            "USERNOTICE" => Event::UserNotice(UserNotice {
+                channel: channel(message)?,
+                room_id: tag(message, "room-id"),
+                id: tag(message, "id"),
+                user: user(message, tag(message, "login")),
+                kind: user_notice_kind(message),
+                system_message: tag(message, "system-msg"),
+                text: message.trailing.map(|s| s.to_string()),
+                emotes: emotes(message),
+                timestamp: tag_timestamp(message, "tmi-sent-ts"),
+            }),
+            "CLEARCHAT" => Event::ClearChat(ClearChat {
+                channel: channel(message)?,
+                target: message.trailing.map(|s| s.to_string()),
+                ban_duration: tag_number(message, "ban-duration").map(Duration::from_secs),
+                timestamp: tag_timestamp(message, "tmi-sent-ts"),
+            }),
+            "CLEARMSG" => Event::ClearMsg(ClearMsg {
+                channel: channel(message)?,
+                login: tag(message, "login"),
+                target_message_id: tag(message, "target-msg-id"),
+                text: message.trailing.map(|s| s.to_string()),
+                timestamp: tag_timestamp(message, "tmi-sent-ts"),
+            }),
+            "ROOMSTATE" => Event::RoomState(RoomState {
+                channel: channel(message)?,
+                room_id: tag(message, "room-id"),
+                emote_only: tag_flag(message, "emote-only"),
+                followers_only: message.tag_value("followers-only").and_then(|v| v.parse().ok()),
+                r9k: tag_flag(message, "r9k"),
+                slow: tag_number(message, "slow"),
+                subs_only: tag_flag(message, "subs-only"),
+            }),
+            "USERSTATE" => Event::UserState(UserState {
+                channel: channel(message)?,
+                user: user(message, None),
+                emote_sets: emote_sets(message),
+            }),
+            "GLOBALUSERSTATE" => Event::GlobalUserState(GlobalUserState {
+                user: user(message, None),
+                emote_sets: emote_sets(message),
+            }),
+            "HOSTTARGET" => {
+                // trailing is "<channel> [<viewers>]", where channel is "-" when hosting stops
+                let mut iter = message.trailing.unwrap_or("").split_whitespace();
+                Event::HostTarget(HostTarget {
+                    channel: channel(message)?,
+                    target: iter.next().filter(|s| *s != "-").map(|s| s.to_string()),
+                    viewers: iter.next().and_then(|s| s.parse().ok()),
+                })
+            }
+            "NOTICE" => Event::Notice(Notice {
+                channel: message
+                    .first_arg_as_channel_name()
+                    .filter(|c| *c != "*")
+                    .map(|c| c.to_string()),
+                msg_id: tag(message, "msg-id"),
+                text: message.trailing.unwrap_or("").to_string(),
+            }),
+            "JOIN" => Event::Join {
+                channel: channel(message)?,
+                login: prefix_login(message).ok_or("User expected")?,
+            },
+            "PART" => Event::Part {
+                channel: channel(message)?,
+                login: prefix_login(message).ok_or("User expected")?,
+            },
+            "PING" => Event::Ping(message.trailing.map(|s| s.to_string())),
+            "PONG" => Event::Pong,
+            "RECONNECT" => Event::Reconnect,
+            other => Event::Other(other.to_string()),
+        })
+    }
+
+    /// User who sent the message, for events which have one.
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            Event::Privmsg(privmsg) => Some(&privmsg.user),
+            Event::Whisper(whisper) => Some(&whisper.user),
+            Event::UserNotice(user_notice) => Some(&user_notice.user),
+            Event::UserState(user_state) => Some(&user_state.user),
+            Event::GlobalUserState(global_user_state) => Some(&global_user_state.user),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(raw: &str) -> Event {
+        Event::from_message(&irc::Message::parse(raw).expect("Failed to parse message"))
+            .expect("Failed to convert message")
+    }
+
+    #[test]
+    fn test_privmsg() {
+        match event(
+            "@badge-info=subscriber/8;badges=moderator/1,subscriber/6;bits=100;color=#0D4200;\
+             display-name=Ronni;emotes=25:0-4,12-16/1902:6-10;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;\
+             room-id=1337;tmi-sent-ts=1507246572675;user-id=1337 \
+             :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :Kappa Keepo Kappa",
+        ) {
+            Event::Privmsg(privmsg) => {
+                assert_eq!(privmsg.channel, "ronni");
+                assert_eq!(privmsg.text, "Kappa Keepo Kappa");
+                assert_eq!(privmsg.id.as_ref().unwrap(), "b34ccfc7-4977-403a-8a94-33c6bac34fb8");
+                assert_eq!(privmsg.bits, Some(100));
+                assert_eq!(privmsg.user.login, "ronni");
+                assert_eq!(privmsg.user.display_name, "Ronni");
+                assert_eq!(privmsg.user.id.as_ref().unwrap(), "1337");
+                assert_eq!(privmsg.user.color.as_ref().unwrap(), "#0D4200");
+                assert!(privmsg.user.is_moderator());
+                assert_eq!(
+                    privmsg.user.badges,
+                    vec![
+                        Badge {
+                            name: "moderator".to_string(),
+                            version: "1".to_string()
+                        },
+                        Badge {
+                            name: "subscriber".to_string(),
+                            version: "6".to_string()
+                        },
+                    ]
+                );
+                assert_eq!(
+                    privmsg.emotes,
+                    vec![
+                        Emote {
+                            id: "25".to_string(),
+                            ranges: vec![(0, 4), (12, 16)]
+                        },
+                        Emote {
+                            id: "1902".to_string(),
+                            ranges: vec![(6, 10)]
+                        },
+                    ]
+                );
+                assert_eq!(
+                    privmsg.timestamp.unwrap(),
+                    UNIX_EPOCH + Duration::from_millis(1507246572675)
+                );
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_display_name_defaults_to_login() {
+        match event("@display-name=;color= :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :hi") {
+            Event::Privmsg(privmsg) => {
+                assert_eq!(privmsg.user.display_name, "ronni");
+                assert!(privmsg.user.color.is_none());
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resub() {
+        match event(
+            "@badges=staff/1,broadcaster/1;color=#008000;display-name=ronni;login=ronni;\
+             msg-id=resub;msg-param-cumulative-months=6;msg-param-streak-months=2;\
+             msg-param-sub-plan=Prime;room-id=1337;tmi-sent-ts=1507246572675;user-id=1337 \
+             :tmi.twitch.tv USERNOTICE #dallas :Great stream -- keep it up!",
+        ) {
+            Event::UserNotice(notice) => {
+                assert_eq!(notice.channel, "dallas");
+                assert_eq!(notice.user.login, "ronni");
+                assert_eq!(notice.text.unwrap(), "Great stream -- keep it up!");
+                match notice.kind {
+                    UserNoticeKind::Resub {
+                        plan,
+                        cumulative_months,
+                        streak_months,
+                    } => {
+                        assert_eq!(plan.unwrap(), "Prime");
+                        assert_eq!(cumulative_months, Some(6));
+                        assert_eq!(streak_months, Some(2));
+                    }
+                    other => assert!(false, "wrong kind: {:?}", other),
+                }
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_raid() {
+        match event(
+            "@display-name=TestChannel;login=testchannel;msg-id=raid;msg-param-displayName=TestChannel;\
+             msg-param-login=testchannel;msg-param-viewerCount=15;room-id=1337;user-id=123456 \
+             :tmi.twitch.tv USERNOTICE #othertestchannel",
+        ) {
+            Event::UserNotice(notice) => match notice.kind {
+                UserNoticeKind::Raid {
+                    login, viewer_count, ..
+                } => {
+                    assert_eq!(login.unwrap(), "testchannel");
+                    assert_eq!(viewer_count, Some(15));
+                }
+                other => assert!(false, "wrong kind: {:?}", other),
+            },
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clearchat() {
+        match event("@ban-duration=350;room-id=1337;target-user-id=1337 :tmi.twitch.tv CLEARCHAT #dallas :ronni") {
+            Event::ClearChat(clear) => {
+                assert_eq!(clear.channel, "dallas");
+                assert_eq!(clear.target.unwrap(), "ronni");
+                assert_eq!(clear.ban_duration, Some(Duration::from_secs(350)));
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+
+        match event(":tmi.twitch.tv CLEARCHAT #dallas") {
+            Event::ClearChat(clear) => {
+                assert!(clear.target.is_none());
+                assert!(clear.ban_duration.is_none());
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_roomstate() {
+        match event("@followers-only=-1;room-id=1337;slow=10 :tmi.twitch.tv ROOMSTATE #dallas") {
+            Event::RoomState(state) => {
+                assert_eq!(state.followers_only, Some(-1));
+                assert_eq!(state.slow, Some(10));
+                assert!(state.emote_only.is_none(), "absent settings should be None");
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hosttarget() {
+        match event(":tmi.twitch.tv HOSTTARGET #hosting_channel :target_channel 42") {
+            Event::HostTarget(host) => {
+                assert_eq!(host.target.unwrap(), "target_channel");
+                assert_eq!(host.viewers, Some(42));
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+
+        match event(":tmi.twitch.tv HOSTTARGET #hosting_channel :- 0") {
+            Event::HostTarget(host) => assert!(host.target.is_none()),
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_notice() {
+        match event("@msg-id=msg_ratelimit :tmi.twitch.tv NOTICE #dallas :You are sending messages too quickly.") {
+            Event::Notice(notice) => {
+                assert_eq!(notice.channel.unwrap(), "dallas");
+                assert_eq!(notice.msg_id.unwrap(), "msg_ratelimit");
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+
+        match event(":tmi.twitch.tv NOTICE * :Login authentication failed") {
+            Event::Notice(notice) => {
+                assert!(notice.channel.is_none());
+                assert!(notice.msg_id.is_none());
+                assert_eq!(notice.text, "Login authentication failed");
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_join_and_whisper() {
+        match event(":ronni!ronni@ronni.tmi.twitch.tv JOIN #dallas") {
+            Event::Join { channel, login } => {
+                assert_eq!(channel, "dallas");
+                assert_eq!(login, "ronni");
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+
+        match event(
+            "@badges=;color=;display-name=Ronni;message-id=3;thread-id=1337_1338;user-id=1337 \
+             :ronni!ronni@ronni.tmi.twitch.tv WHISPER bot :hello there",
+        ) {
+            Event::Whisper(whisper) => {
+                assert_eq!(whisper.recipient, "bot");
+                assert_eq!(whisper.user.login, "ronni");
+                assert_eq!(whisper.text, "hello there");
+            }
+            other => assert!(false, "wrong event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_user_of_event() {
+        let privmsg = event("@display-name=Ronni :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :hi");
+        assert_eq!(privmsg.user().map(|user| user.display_name.as_str()), Some("Ronni"));
+
+        let join = event(":ronni!ronni@ronni.tmi.twitch.tv JOIN #dallas");
+        assert!(join.user().is_none());
+    }
+
+    #[test]
+    fn test_privmsg_without_channel_is_an_error() {
+        let message = irc::Message::parse(":ronni!ronni@ronni.tmi.twitch.tv PRIVMSG :hi").unwrap();
+        assert!(Event::from_message(&message).is_err());
+    }
+}
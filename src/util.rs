@@ -1,3 +1,5 @@
+use unicode_segmentation::UnicodeSegmentation;
+
 /// Modifies message in such way that it is visually remains the same,
 /// but Twitch IRC does not consider message a duplicate.
 ///
@@ -53,6 +55,112 @@ pub fn modify_message(message: &mut String, salt: usize) {
     message.push(SUFFIX[salt % SUFFIX.len()]);
 }
 
+/// Makes message text safe to be sent as a single chat message: line breaks and other control characters
+/// (which would otherwise let text escape PRIVMSG and be interpreted as further IRC commands) are replaced
+/// with spaces, and surrounding whitespace is removed.
+///
+/// Twitch runs messages starting with '/' or '.' as chat commands (e.g. `/ban`), so such messages are
+/// prefixed with an invisible character, which adds at most 1 character to the message.
+pub fn sanitize_message(message: &str) -> String {
+    const COMMAND_ESCAPE: char = '\u{e0000}';
+
+    let sanitized = message
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_string();
+
+    if sanitized.starts_with('/') || sanitized.starts_with('.') {
+        let mut escaped = String::with_capacity(sanitized.len() + COMMAND_ESCAPE.len_utf8());
+        escaped.push(COMMAND_ESCAPE);
+        escaped.push_str(&sanitized);
+        escaped
+    } else {
+        sanitized
+    }
+}
+
+/// Takes the longest prefix of a text which is no longer than `limit` characters and doesn't end in
+/// the middle of a grapheme cluster.
+fn take_graphemes(text: &str, limit: usize) -> &str {
+    let mut length = 0;
+    let mut end = 0;
+    for (offset, grapheme) in text.grapheme_indices(true) {
+        length += grapheme.chars().count();
+        if length > limit {
+            break;
+        }
+        end = offset + grapheme.len();
+    }
+    &text[..end]
+}
+
+/// Shortens message to at most `limit` characters, replacing the end with an ellipsis. Message is cut
+/// between words if possible, so that emotes are not left half-written.
+pub fn truncate_message(message: &str, limit: usize) -> String {
+    const ELLIPSIS: char = '\u{2026}';
+
+    if message.chars().count() <= limit {
+        return message.to_string();
+    }
+
+    let prefix = take_graphemes(message, limit.saturating_sub(1));
+    let cut_inside_word = !message[prefix.len()..].starts_with(char::is_whitespace);
+    let prefix = match prefix.rfind(char::is_whitespace) {
+        Some(boundary) if cut_inside_word => &prefix[..boundary],
+        _ => prefix,
+    };
+
+    let mut truncated = prefix.trim_end().to_string();
+    truncated.push(ELLIPSIS);
+    truncated
+}
+
+/// Splits message into parts of at most `limit` characters. Message is split between words, so that
+/// emotes stay intact; words which are longer than the limit themselves are split between grapheme clusters.
+pub fn split_message(message: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut current_length = 0;
+
+    for word in message.split_whitespace() {
+        let mut word = word;
+        let mut word_length = word.chars().count();
+
+        if current_length > 0 && current_length + 1 + word_length > limit {
+            parts.push(std::mem::replace(&mut current, String::new()));
+            current_length = 0;
+        }
+
+        while word_length > limit {
+            let chunk = take_graphemes(word, limit);
+            if chunk.is_empty() {
+                // a single grapheme cluster longer than limit, no way to avoid breaking it
+                break;
+            }
+            parts.push(chunk.to_string());
+            word = &word[chunk.len()..];
+            word_length = word.chars().count();
+        }
+
+        if word.is_empty() {
+            continue;
+        }
+        if current_length > 0 {
+            current.push(' ');
+            current_length += 1;
+        }
+        current.push_str(word);
+        current_length += word_length;
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -83,4 +191,62 @@ mod tests {
         modify_message(&mut message, 123123123);
         assert!(message.len() <= original_len + 12);
     }
+
+    #[test]
+    fn test_sanitize_message_removes_line_breaks() {
+        assert_eq!(
+            sanitize_message(" hello\r\nPRIVMSG #forsen :hi\t\u{0}"),
+            "hello  PRIVMSG #forsen :hi"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_message_escapes_chat_commands() {
+        assert_eq!(sanitize_message("/ban forsen"), "\u{e0000}/ban forsen");
+        assert_eq!(sanitize_message(" .clear"), "\u{e0000}.clear");
+        assert_eq!(sanitize_message("see /help"), "see /help");
+
+        // sanitizing twice doesn't change anything
+        let once = sanitize_message("/clear");
+        assert_eq!(sanitize_message(&once), once);
+    }
+
+    #[test]
+    fn test_short_messages_are_left_intact() {
+        assert_eq!(truncate_message("hello there", 11), "hello there");
+        assert_eq!(split_message("hello there", 11), vec!["hello there".to_string()]);
+    }
+
+    #[test]
+    fn test_truncate_message_cuts_between_words() {
+        assert_eq!(truncate_message("hello there forsenE", 15), "hello there\u{2026}");
+        assert_eq!(truncate_message("hello there forsenE", 13), "hello there\u{2026}");
+        assert_eq!(truncate_message("hellothere", 6), "hello\u{2026}");
+    }
+
+    #[test]
+    fn test_truncate_message_keeps_graphemes() {
+        // family emoji is a single grapheme cluster made of 7 characters
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}\u{200d}\u{1f466}";
+        let message = format!("ab{}", family);
+
+        assert_eq!(truncate_message(&message, 8), "ab\u{2026}");
+        assert!(truncate_message(&message, 8).chars().count() <= 8);
+    }
+
+    #[test]
+    fn test_split_message_keeps_words() {
+        let parts = split_message("forsenE forsenE pajaS OMEGALUL", 16);
+
+        assert_eq!(parts, vec!["forsenE forsenE", "pajaS OMEGALUL"]);
+    }
+
+    #[test]
+    fn test_split_message_breaks_long_words() {
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}\u{200d}\u{1f466}";
+        let parts = split_message(&format!("hi {0}{0}", family), 10);
+
+        assert_eq!(parts, vec!["hi".to_string(), family.to_string(), family.to_string()]);
+        assert!(parts.iter().all(|part| part.chars().count() <= 10));
+    }
 }
@@ -0,0 +1,338 @@
+//! A local stand-in for Twitch IRC, speaking just enough of its dialect to run the bot against it.
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_trait::async_trait;
+use async_tungstenite::{accept_async, WebSocketStream};
+use futures::{SinkExt, StreamExt};
+use tungstenite::Message;
+use url::Url;
+
+use bot::banphrase::{BanphraseChecker, BanphraseError};
+use bot::prelude::*;
+use bot::transport::WebSocketTransport;
+use bot::{Bot, BotBuilder, BotError, ShutdownHandle};
+
+pub const USERNAME: &str = "bot";
+
+pub const TOKEN: &str = "token";
+
+/// How long we wait for the bot to do something before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+static MESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct MockTwitch {
+    listener: TcpListener,
+    pub url: Url,
+}
+
+impl MockTwitch {
+    pub async fn start() -> MockTwitch {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let listener = TcpListener::from(listener);
+        let address = listener.local_addr().expect("failed to get local address");
+        let url = Url::parse(&format!("ws://{}", address)).unwrap();
+        MockTwitch { listener, url }
+    }
+
+    /// Waits for the bot to connect.
+    pub async fn accept(&self) -> MockConnection {
+        let (stream, _) = tokio::timer::Timeout::new(self.listener.accept(), TIMEOUT)
+            .await
+            .expect("bot didn't connect in time")
+            .expect("failed to accept connection");
+        let ws = accept_async(stream).await.expect("websocket handshake failed");
+        MockConnection {
+            ws,
+            received: VecDeque::new(),
+        }
+    }
+}
+
+pub struct MockConnection {
+    ws: WebSocketStream<TcpStream>,
+    received: VecDeque<String>,
+}
+
+impl MockConnection {
+    pub async fn send(&mut self, line: &str) {
+        self.ws
+            .send(Message::text(format!("{}\r\n", line)))
+            .await
+            .expect("failed to send line to bot");
+    }
+
+    /// Receives next line sent by the bot, or `None` if the bot has closed connection.
+    pub async fn recv(&mut self) -> Option<String> {
+        loop {
+            if let Some(line) = self.received.pop_front() {
+                return Some(line);
+            }
+            match tokio::timer::Timeout::new(self.ws.next(), TIMEOUT)
+                .await
+                .expect("bot didn't send anything in time")
+            {
+                Some(Ok(Message::Text(text))) => {
+                    self.received
+                        .extend(text.split_terminator("\r\n").map(|s| s.to_string()));
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    pub async fn expect(&mut self, line: &str) {
+        assert_eq!(self.recv().await.as_deref(), Some(line));
+    }
+
+    /// Receives a line, making sure it starts with a given prefix.
+    pub async fn expect_prefix(&mut self, prefix: &str) -> String {
+        let line = self.recv().await.expect("connection is closed");
+        assert!(line.starts_with(prefix), "expected {:?}..., got {:?}", prefix, line);
+        line
+    }
+
+    /// Receives lines until one starting with a given prefix is found.
+    pub async fn skip_until(&mut self, prefix: &str) -> String {
+        loop {
+            let line = self.recv().await.expect("connection is closed");
+            if line.starts_with(prefix) {
+                return line;
+            }
+        }
+    }
+
+    /// Performs login handshake.
+    pub async fn login(&mut self) {
+        self.expect(&format!("PASS oauth:{}", TOKEN)).await;
+        self.expect(&format!("NICK {}", USERNAME)).await;
+        let caps = self.expect_prefix("CAP REQ :").await;
+        self.send(&format!(":tmi.twitch.tv CAP * ACK :{}", &caps["CAP REQ :".len()..]))
+            .await;
+
+        for (code, text) in &[
+            ("001", "Welcome, GLHF!"),
+            ("002", "Your host is tmi.twitch.tv"),
+            ("003", "This server is rather new"),
+            ("004", "-"),
+            ("375", "-"),
+            ("372", "You are in a maze of twisty passages, all alike."),
+            ("376", ">"),
+        ] {
+            self.send(&format!(":tmi.twitch.tv {} {} :{}", code, USERNAME, text))
+                .await;
+        }
+        self.send(&format!(
+            "@badge-info=;badges=;color=;display-name={};emote-sets=0;user-id=1;user-type= \
+             :tmi.twitch.tv GLOBALUSERSTATE",
+            USERNAME
+        ))
+        .await;
+    }
+
+    /// Waits for the bot to join a channel and confirms it.
+    pub async fn expect_join(&mut self, channel: &str) {
+        self.expect(&format!("JOIN #{}", channel)).await;
+        self.send(&format!(":{0}!{0}@{0}.tmi.twitch.tv JOIN #{1}", USERNAME, channel))
+            .await;
+        self.user_state(channel, "").await;
+        self.send(&format!(
+            "@emote-only=0;followers-only=-1;r9k=0;rituals=0;room-id=2;slow=0;subs-only=0 \
+             :tmi.twitch.tv ROOMSTATE #{}",
+            channel
+        ))
+        .await;
+    }
+
+    /// Tells the bot about its badges in a channel.
+    pub async fn user_state(&mut self, channel: &str, badges: &str) {
+        self.send(&format!(
+            "@badge-info=;badges={};color=;display-name={};emote-sets=0;mod={};subscriber=0;user-type= \
+             :tmi.twitch.tv USERSTATE #{}",
+            badges,
+            USERNAME,
+            if badges.contains("moderator") { 1 } else { 0 },
+            channel
+        ))
+        .await;
+    }
+
+    /// Sends a chat message from a given user.
+    pub async fn privmsg(&mut self, channel: &str, login: &str, text: &str) {
+        self.send(&format!(
+            "@badge-info=;badges=;color=;display-name={0};emotes=;flags=;id=message-{1};mod=0;room-id=2;\
+             subscriber=0;tmi-sent-ts=0;turbo=0;user-id=3;user-type= :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #{2} :{3}",
+            login,
+            MESSAGE_ID.fetch_add(1, Ordering::SeqCst),
+            channel,
+            text
+        ))
+        .await;
+    }
+
+    pub async fn whisper(&mut self, login: &str, text: &str) {
+        self.send(&format!(
+            "@badges=;color=;display-name={0};emotes=;message-id={1};thread-id=3_4;turbo=0;user-id=3;\
+             user-type= :{0}!{0}@{0}.tmi.twitch.tv WHISPER {2} :{3}",
+            login,
+            MESSAGE_ID.fetch_add(1, Ordering::SeqCst),
+            USERNAME,
+            text
+        ))
+        .await;
+    }
+
+    pub async fn notice(&mut self, channel: &str, msg_id: &str, text: &str) {
+        self.send(&format!(
+            "@msg-id={} :tmi.twitch.tv NOTICE #{} :{}",
+            msg_id, channel, text
+        ))
+        .await;
+    }
+}
+
+pub struct NothingIsBanned;
+
+#[async_trait]
+impl BanphraseChecker for NothingIsBanned {
+    async fn is_banned(&self, _: &str, _: &str) -> Result<bool, BanphraseError> {
+        Ok(false)
+    }
+}
+
+/// Remembers every message it is asked about, banning nothing.
+#[derive(Clone, Default)]
+pub struct RecordingChecker {
+    pub checked: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl BanphraseChecker for RecordingChecker {
+    async fn is_banned(&self, _: &str, message: &str) -> Result<bool, BanphraseError> {
+        self.checked.lock().unwrap().push(message.to_string());
+        Ok(false)
+    }
+}
+
+/// Replies with whatever it is given.
+pub struct Echo(pub Overflow);
+
+#[async_trait]
+impl ExecutableCommand<()> for Echo {
+    async fn execute<'a>(
+        &self,
+        command: &'a str,
+        message: irc::Message<'a>,
+        _: &'a twitch::Event,
+        _: &BotState<()>,
+    ) -> ExecutionOutcome {
+        ExecutionOutcome::respond(&message, command.to_string())
+    }
+
+    fn help(&self) -> String {
+        "echo <message> -- echoes message back".to_string()
+    }
+
+    fn cooldown(&self) -> CommandCooldown {
+        // only per-user cooldown, so that different users can use it in quick succession
+        CommandCooldown {
+            command: None,
+            user: Some(Duration::from_millis(1)),
+        }
+    }
+
+    fn level(&self) -> PermissionLevel {
+        PermissionLevel::User
+    }
+
+    fn overflow(&self) -> Overflow {
+        self.0
+    }
+}
+
+/// Leaves the channel it is used in, saying goodbye.
+pub struct Leave;
+
+#[async_trait]
+impl ExecutableCommand<()> for Leave {
+    async fn execute<'a>(
+        &self,
+        _: &'a str,
+        message: irc::Message<'a>,
+        _: &'a twitch::Event,
+        _: &BotState<()>,
+    ) -> ExecutionOutcome {
+        match valid_channel_name(message.channel().unwrap_or_default()) {
+            Ok(channel) => ExecutionOutcome::respond(&message, "bye".to_string()).then_part(channel),
+            Err(err) => ExecutionOutcome::Error(err.to_string()),
+        }
+    }
+
+    fn help(&self) -> String {
+        "leave -- leaves this channel".to_string()
+    }
+
+    fn cooldown(&self) -> CommandCooldown {
+        CommandCooldown {
+            command: Some(Duration::from_millis(1)),
+            user: None,
+        }
+    }
+
+    fn level(&self) -> PermissionLevel {
+        PermissionLevel::User
+    }
+}
+
+/// Bot connected to a given mock server, joining channel `test` with `echo` command (and its `split_echo`
+/// variant, which splits long replies instead of truncating them) and `leave` command.
+pub fn bot_builder(url: Url) -> BotBuilder<()> {
+    Bot::builder()
+        .credentials(USERNAME, TOKEN)
+        .channel("test")
+        .command("echo", Echo(Overflow::Truncate))
+        .command("split_echo", Echo(Overflow::Split))
+        .command("leave", Leave)
+        .banphrase(NothingIsBanned)
+        .transport(WebSocketTransport::new(url))
+        .data(())
+}
+
+/// Runs the bot against mock server, following a script. Bot is asked to shut down once script is done.
+pub fn run<C, S, F>(configure: C, script: S) -> Result<(), BotError>
+where
+    C: FnOnce(BotBuilder<()>) -> BotBuilder<()>,
+    S: FnOnce(MockTwitch, ShutdownHandle) -> F,
+    F: Future<Output = ()>,
+{
+    let runtime = tokio::runtime::Builder::new()
+        .build()
+        .expect("Failed to create runtime");
+
+    runtime.block_on(async move {
+        let twitch = MockTwitch::start().await;
+        let bot = configure(bot_builder(twitch.url.clone()))
+            .build()
+            .expect("Failed to build bot");
+        let shutdown = bot.shutdown_handle();
+
+        let script = {
+            let shutdown = shutdown.clone();
+            async move {
+                script(twitch, shutdown.clone()).await;
+                shutdown.shutdown();
+            }
+        };
+
+        futures::future::join(bot, script).await.0
+    })
+}
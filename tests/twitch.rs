@@ -0,0 +1,354 @@
+//! End-to-end tests running the bot against a local mock of Twitch IRC.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bot::{BotError, ConnectionError, Settings};
+
+use common::*;
+
+#[test]
+fn test_bot_logs_in_and_joins_channels() {
+    let result = run(
+        |builder| builder.channel("other"),
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            // channels are joined in alphabetical order
+            connection.expect_join("other").await;
+            connection.expect_join("test").await;
+        },
+    );
+
+    // bot should not report any errors if it was stopped
+    if let Err(error) = result {
+        assert!(false, "bot has failed: {}", error);
+    }
+}
+
+#[test]
+fn test_command_is_answered() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.privmsg("test", "user", ">> echo hello there").await;
+            connection.expect("PRIVMSG #test :hello there").await;
+        },
+    );
+}
+
+#[test]
+fn test_whispered_command_is_answered_with_whisper() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.whisper("user", ">> echo psst").await;
+            connection.expect("PRIVMSG #jtv :/w user psst").await;
+        },
+    );
+}
+
+#[test]
+fn test_ping_is_answered() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.send("PING :tmi.twitch.tv").await;
+            connection.expect("PONG :tmi.twitch.tv").await;
+        },
+    );
+}
+
+#[test]
+fn test_bot_reconnects_when_asked_to() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.send(":tmi.twitch.tv RECONNECT").await;
+
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.privmsg("test", "user", ">> echo still here").await;
+            connection.expect("PRIVMSG #test :still here").await;
+        },
+    );
+}
+
+#[test]
+fn test_duplicate_message_is_retried() {
+    let _ = run(
+        |builder| {
+            builder.settings(Settings {
+                channel_cooldown: Duration::from_millis(50),
+                ..Settings::default()
+            })
+        },
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.privmsg("test", "user", ">> echo hello").await;
+            connection.expect("PRIVMSG #test :hello").await;
+
+            connection
+                .notice(
+                    "test",
+                    "msg_duplicate",
+                    "Your message was not sent because it is identical to the previous one you sent, \
+                     less than 30 seconds ago.",
+                )
+                .await;
+            let retried = connection.expect_prefix("PRIVMSG #test :hello").await;
+            assert_ne!(retried, "PRIVMSG #test :hello", "retried message should be modified");
+        },
+    );
+}
+
+#[test]
+fn test_banphrase_checker_sees_exactly_what_is_sent() {
+    let checker = RecordingChecker::default();
+    let checked = checker.checked.clone();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+
+    let _ = run(
+        |builder| {
+            builder.banphrase(checker).settings(Settings {
+                channel_cooldown: Duration::from_millis(50),
+                ..Settings::default()
+            })
+        },
+        |twitch, _| {
+            let sent = sent.clone();
+            async move {
+                let mut connection = twitch.accept().await;
+                connection.login().await;
+                connection.expect_join("test").await;
+
+                // the second reply has to be modified to not be a duplicate
+                for user in &["alice", "bob"] {
+                    connection.privmsg("test", user, ">> echo hello").await;
+                    let line = connection.expect_prefix("PRIVMSG #test :").await;
+                    sent.lock().unwrap().push(line["PRIVMSG #test :".len()..].to_string());
+                }
+            }
+        },
+    );
+
+    let sent = sent.lock().unwrap().clone();
+    assert_ne!(sent[0], sent[1]);
+    assert_eq!(*checked.lock().unwrap(), sent);
+}
+
+#[test]
+fn test_long_replies_are_truncated() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            let words = vec!["forsenE"; 100].join(" ");
+            connection.privmsg("test", "user", &format!(">> echo {}", words)).await;
+
+            let line = connection.expect_prefix("PRIVMSG #test :forsenE").await;
+            let text = &line["PRIVMSG #test :".len()..];
+            assert!(
+                text.chars().count() <= 500,
+                "reply is too long: {}",
+                text.chars().count()
+            );
+            assert!(
+                text.ends_with("forsenE\u{2026}"),
+                "reply should end with a whole word: {}",
+                text
+            );
+        },
+    );
+}
+
+#[test]
+fn test_long_replies_are_split() {
+    let cooldown = Duration::from_millis(300);
+
+    let _ = run(
+        |builder| {
+            builder.settings(Settings {
+                channel_cooldown: cooldown,
+                ..Settings::default()
+            })
+        },
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            let words = vec!["forsenE"; 100].join(" ");
+            connection
+                .privmsg("test", "user", &format!(">> split_echo {}", words))
+                .await;
+
+            let first = connection.expect_prefix("PRIVMSG #test :").await;
+            let sent_at = Instant::now();
+            let second = connection.expect_prefix("PRIVMSG #test :").await;
+            assert!(
+                sent_at.elapsed() >= cooldown - Duration::from_millis(50),
+                "parts should be sent respecting cooldown"
+            );
+
+            let parts: Vec<&str> = [&first, &second]
+                .iter()
+                .map(|line| &line["PRIVMSG #test :".len()..])
+                .collect();
+            assert!(parts.iter().all(|part| part.chars().count() <= 500));
+            assert_eq!(parts.join(" "), words);
+        },
+    );
+}
+
+#[test]
+fn test_login_failure_stops_bot() {
+    let result = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.expect_prefix("PASS").await;
+            connection.expect_prefix("NICK").await;
+            connection.expect_prefix("CAP REQ").await;
+            connection
+                .send(":tmi.twitch.tv NOTICE * :Login authentication failed")
+                .await;
+
+            // bot should give up instead of trying to connect again
+            assert_eq!(connection.recv().await, None);
+        },
+    );
+
+    match result {
+        Err(BotError::Connection(ConnectionError::LoginFailed(_))) => assert!(true),
+        Err(error) => assert!(false, "unexpected error: {}", error),
+        Ok(()) => assert!(false, "bot should have failed to log in"),
+    }
+}
+
+#[test]
+fn test_channel_cooldown_is_respected() {
+    let cooldown = Duration::from_millis(500);
+
+    let _ = run(
+        |builder| {
+            builder.settings(Settings {
+                channel_cooldown: cooldown,
+                ..Settings::default()
+            })
+        },
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.privmsg("test", "alice", ">> echo first").await;
+            connection.expect("PRIVMSG #test :first").await;
+            let sent_at = Instant::now();
+
+            connection.privmsg("test", "bob", ">> echo second").await;
+            connection.expect("PRIVMSG #test :second").await;
+            let elapsed = sent_at.elapsed();
+
+            // allow for some timer imprecision
+            assert!(
+                elapsed >= cooldown - Duration::from_millis(50),
+                "second message was sent too early: {:?}",
+                elapsed
+            );
+        },
+    );
+}
+
+#[test]
+fn test_moderator_cooldown_is_shorter() {
+    let cooldown = Duration::from_millis(1000);
+
+    let _ = run(
+        |builder| {
+            builder.settings(Settings {
+                channel_cooldown: cooldown,
+                ..Settings::default()
+            })
+        },
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+            connection.user_state("test", "moderator/1").await;
+
+            connection.privmsg("test", "alice", ">> echo first").await;
+            connection.expect("PRIVMSG #test :first").await;
+            let sent_at = Instant::now();
+
+            connection.privmsg("test", "bob", ">> echo second").await;
+            connection.expect("PRIVMSG #test :second").await;
+            let elapsed = sent_at.elapsed();
+
+            assert!(
+                elapsed < cooldown / 2,
+                "moderator cooldown should have been used, but waited {:?}",
+                elapsed
+            );
+        },
+    );
+}
+
+#[test]
+fn test_reply_is_sent_before_leaving_channel() {
+    let _ = run(
+        |builder| builder,
+        |twitch, _| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            connection.privmsg("test", "user", ">> leave").await;
+            connection.expect("PRIVMSG #test :bye").await;
+            connection.expect("PART #test").await;
+        },
+    );
+}
+
+#[test]
+fn test_channels_are_left_on_shutdown() {
+    let _ = run(
+        |builder| builder,
+        |twitch, shutdown| async move {
+            let mut connection = twitch.accept().await;
+            connection.login().await;
+            connection.expect_join("test").await;
+
+            shutdown.shutdown();
+
+            connection.expect("PART #test").await;
+            assert_eq!(connection.recv().await, None);
+        },
+    );
+}